@@ -0,0 +1,226 @@
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Error, Result};
+use base58::{FromBase58, ToBase58};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{bech32, params::ADDRESS_HRP, scheme::SchemeTag, wallet::hash_pubkey};
+
+const PUBKEY_HASH_LEN: usize = 20;
+const CHECK_SUM_LEN: usize = 4;
+
+// 地址的文本格式，两种格式对应同样的输出
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+pub enum AddressEncoding {
+    // base58(版本 + pubkey hash + 校验和)
+    #[default]
+    Base58,
+    // hrp + bech32m(版本 + pubkey hash)，不区分大小写，可以找到输错的字符
+    Bech32,
+}
+
+// 版本字节表示公钥使用的签名算法，encoding 只影响显示，不参与比较和 hash
+#[derive(Debug, Clone, Copy)]
+pub struct Address {
+    scheme: SchemeTag,
+    pubkey_hash: [u8; PUBKEY_HASH_LEN],
+    encoding: AddressEncoding,
+}
+
+impl Address {
+    pub fn new(pubkey_hash: &[u8], scheme: SchemeTag) -> Result<Self> {
+        let pubkey_hash = pubkey_hash
+            .try_into()
+            .map_err(|_| anyhow!("Pubkey hash must be {PUBKEY_HASH_LEN} bytes"))?;
+        Ok(Self {
+            scheme,
+            pubkey_hash,
+            encoding: AddressEncoding::default(),
+        })
+    }
+
+    pub fn from_pubkey(pubkey: &Vec<u8>, scheme: SchemeTag) -> Self {
+        Self {
+            scheme,
+            pubkey_hash: hash_pubkey(pubkey)
+                .try_into()
+                .expect("ripemd160 output is 20 bytes"),
+            encoding: AddressEncoding::default(),
+        }
+    }
+
+    // 同一个地址换一种格式显示
+    pub fn with_encoding(self, encoding: AddressEncoding) -> Self {
+        Self { encoding, ..self }
+    }
+
+    pub fn encoding(&self) -> AddressEncoding {
+        self.encoding
+    }
+
+    // hex 编码，和输出中保存的格式相同
+    pub fn pubkey_hash(&self) -> String {
+        hex::encode(self.pubkey_hash)
+    }
+
+    pub fn scheme(&self) -> SchemeTag {
+        self.scheme
+    }
+}
+
+impl PartialEq for Address {
+    fn eq(&self, other: &Self) -> bool {
+        self.scheme == other.scheme && self.pubkey_hash == other.pubkey_hash
+    }
+}
+
+impl Eq for Address {}
+
+impl Hash for Address {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.scheme.hash(state);
+        self.pubkey_hash.hash(state);
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut payload = vec![self.scheme.address_version()];
+        payload.extend_from_slice(&self.pubkey_hash);
+        match self.encoding {
+            AddressEncoding::Base58 => {
+                let check_sum = check_sum(&payload);
+                payload.extend_from_slice(&check_sum);
+                write!(f, "{}", payload.to_base58())
+            }
+            AddressEncoding::Bech32 => {
+                let data = bech32::convert_bits(&payload, 8, 5, true).map_err(|_| fmt::Error)?;
+                write!(f, "{}", bech32::encode(ADDRESS_HRP, &data))
+            }
+        }
+    }
+}
+
+// 依次检查编码、长度、版本字节和校验和，带网络前缀的按 bech32 解析
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self> {
+        if address
+            .to_ascii_lowercase()
+            .starts_with(&format!("{ADDRESS_HRP}1"))
+        {
+            return Self::from_bech32(address);
+        }
+        let payload = address
+            .from_base58()
+            .map_err(|e| anyhow!("Address {address} is not valid base58: {e:?}"))?;
+        if payload.len() != 1 + PUBKEY_HASH_LEN + CHECK_SUM_LEN {
+            return Err(anyhow!("Address {address} has a wrong length"));
+        }
+        let (data, sum) = payload.split_at(1 + PUBKEY_HASH_LEN);
+        let scheme = SchemeTag::from_address_version(data[0]).ok_or(anyhow!(
+            "Address {address} has an unknown version {}",
+            data[0]
+        ))?;
+        if check_sum(data) != sum {
+            return Err(anyhow!("Address {address} has a wrong checksum"));
+        }
+        Self::new(&data[1..], scheme)
+    }
+}
+
+impl Address {
+    fn from_bech32(address: &str) -> Result<Self> {
+        let (hrp, data) = bech32::decode(address).map_err(|e| anyhow!("Address {address}: {e}"))?;
+        if hrp != ADDRESS_HRP {
+            return Err(anyhow!("Address {address} has a wrong prefix {hrp}"));
+        }
+        let payload = bech32::convert_bits(&data, 5, 8, false)
+            .map_err(|e| anyhow!("Address {address}: {e}"))?;
+        if payload.len() != 1 + PUBKEY_HASH_LEN {
+            return Err(anyhow!("Address {address} has a wrong length"));
+        }
+        let scheme = SchemeTag::from_address_version(payload[0]).ok_or(anyhow!(
+            "Address {address} has an unknown version {}",
+            payload[0]
+        ))?;
+        Ok(Self::new(&payload[1..], scheme)?.with_encoding(AddressEncoding::Bech32))
+    }
+}
+
+fn check_sum(payload: &[u8]) -> [u8; CHECK_SUM_LEN] {
+    let first_sha = sha256::digest(payload);
+    let second_sha = sha256::digest(first_sha).into_bytes();
+    let mut sum = [0u8; CHECK_SUM_LEN];
+    sum.copy_from_slice(&second_sha[..CHECK_SUM_LEN]);
+    sum
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wallet::Wallet;
+
+    use super::{Address, AddressEncoding};
+
+    #[test]
+    fn test_parse_address() {
+        let wallet = Wallet::new_wallet();
+        let text = wallet.get_address();
+        let address: Address = text.parse().unwrap();
+        assert_eq!(address.to_string(), text);
+        assert_eq!(
+            address,
+            Address::from_pubkey(&wallet.public_key, wallet.scheme)
+        );
+
+        // 改动一个字符后校验和不再匹配
+        let last = text.chars().last().unwrap();
+        let typo = format!(
+            "{}{}",
+            &text[..text.len() - 1],
+            if last == '1' { '2' } else { '1' }
+        );
+        let err = typo.parse::<Address>().unwrap_err().to_string();
+        assert!(err.contains("checksum") || err.contains("length"), "{err}");
+        assert!("".parse::<Address>().is_err());
+        assert!("1".parse::<Address>().is_err());
+        assert!("0OIl".parse::<Address>().is_err());
+
+        let mut payload = base58::FromBase58::from_base58(text.as_str()).unwrap();
+        payload[0] = 0x42;
+        let err = base58::ToBase58::to_base58(payload.as_slice())
+            .parse::<Address>()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown version 66"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_bech32_address() {
+        let wallet = Wallet::new_wallet();
+        let address = wallet.address().with_encoding(AddressEncoding::Bech32);
+        let text = address.to_string();
+        assert!(text.starts_with("sbtc1"), "{text}");
+        assert_eq!(text.parse::<Address>().unwrap(), address);
+        assert_eq!(text.to_uppercase().parse::<Address>().unwrap(), address);
+        // 两种格式是同一个地址
+        assert_eq!(text.parse::<Address>().unwrap(), wallet.address());
+        let both: std::collections::HashSet<Address> =
+            [address, wallet.address()].into_iter().collect();
+        assert_eq!(both.len(), 1);
+
+        let mut typo = text.clone().into_bytes();
+        typo[10] = if typo[10] == b'q' { b'p' } else { b'q' };
+        let err = String::from_utf8(typo)
+            .unwrap()
+            .parse::<Address>()
+            .unwrap_err();
+        assert!(err.to_string().ends_with("typo at position 11"), "{err}");
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::crypter::KEY_LEN;
+
+// walletpassphrase 解锁后派生的密钥只保存在这个后台进程的内存中，
+// 其他命令通过钱包旁边的 unix socket 取得密钥，超时或者 walletlock 后进程退出并删除 socket
+
+// 启动新的 agent，通过 stdin 传递密钥，等它开始监听后返回
+pub fn spawn(socket: &Path, key: &[u8; KEY_LEN], timeout: u64) -> Result<()> {
+    stop(socket)?;
+    let mut child = Command::new(env::current_exe()?)
+        .arg("walletagent")
+        .arg("--socket")
+        .arg(socket)
+        .arg("--timeout")
+        .arg(timeout.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(hex::encode(key).as_bytes())?;
+    }
+    let mut line = String::new();
+    if let Some(stdout) = child.stdout.take() {
+        BufReader::new(stdout).read_line(&mut line)?;
+    }
+    if line.trim() != "ready" {
+        return Err(anyhow!("Wallet agent failed to start"));
+    }
+    Ok(())
+}
+
+// walletagent 命令的入口，从 stdin 读取密钥
+pub fn serve(socket: &Path, timeout: u64) -> Result<()> {
+    let mut key = String::new();
+    io::stdin().read_to_string(&mut key)?;
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let listener = bind(socket)?;
+    println!("ready");
+    io::stdout().flush()?;
+    run(listener, socket, key.trim(), deadline)
+}
+
+fn bind(socket: &Path) -> Result<UnixListener> {
+    remove_socket(socket)?;
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn run(listener: UnixListener, socket: &Path, key: &str, deadline: Instant) -> Result<()> {
+    while Instant::now() < deadline {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // 先删除 socket 再断开连接，stop 返回后可以立即启动新的 agent
+                if handle(&stream, key).unwrap_or(false) {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50))
+            }
+            Err(e) => {
+                remove_socket(socket)?;
+                return Err(e.into());
+            }
+        }
+    }
+    remove_socket(socket)
+}
+
+// 每个连接发送一行命令，返回是否需要退出
+fn handle(mut stream: &UnixStream, key: &str) -> Result<bool> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match line.trim() {
+        "key" => {
+            writeln!(stream, "{key}")?;
+            Ok(false)
+        }
+        "lock" => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+// 没有运行中的 agent 时钱包处于锁定状态
+pub fn request_key(socket: &Path) -> Result<Option<[u8; KEY_LEN]>> {
+    let Some(mut stream) = connect(socket)? else {
+        return Ok(None);
+    };
+    stream.write_all(b"key\n")?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let key = hex::decode(line.trim())?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid key from the wallet agent"))?;
+    Ok(Some(key))
+}
+
+// 通知 agent 退出，等它关闭连接，同时清理崩溃后留下的 socket
+pub fn stop(socket: &Path) -> Result<()> {
+    if let Some(mut stream) = connect(socket)? {
+        stream.write_all(b"lock\n")?;
+        stream.read_to_end(&mut vec![])?;
+    }
+    remove_socket(socket)
+}
+
+fn connect(socket: &Path) -> Result<Option<UnixStream>> {
+    match UnixStream::connect(socket) {
+        Ok(stream) => {
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            Ok(Some(stream))
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_socket(socket: &Path) -> Result<()> {
+    match fs::remove_file(socket) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{bind, request_key, run, stop};
+
+    #[test]
+    fn test_agent_unlock() {
+        let socket = std::env::temp_dir().join(format!("btc-agent-test-{}", std::process::id()));
+        let key = [7u8; 32];
+        let serve = |timeout: Duration| {
+            let listener = bind(&socket).unwrap();
+            let socket = socket.clone();
+            let deadline = Instant::now() + timeout;
+            thread::spawn(move || run(listener, &socket, &hex::encode(key), deadline))
+        };
+
+        let agent = serve(Duration::from_secs(30));
+        assert_eq!(request_key(&socket).unwrap(), Some(key));
+        stop(&socket).unwrap();
+        agent.join().unwrap().unwrap();
+        assert_eq!(request_key(&socket).unwrap(), None);
+
+        // 超时后 agent 删除 socket 并退出
+        let agent = serve(Duration::from_millis(200));
+        agent.join().unwrap().unwrap();
+        assert!(!socket.exists());
+        assert_eq!(request_key(&socket).unwrap(), None);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+// Bech32m 编码 (BIP-350)：hrp + '1' + 5 bit 数据 + 6 个字符的 BCH 校验和
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const SEPARATOR: char = '1';
+const CHECKSUM_LEN: usize = 6;
+const CONST: u32 = 0x2bc830a3;
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+// 这个长度以内的 BCH 码距离为 5，最多两个错误的位置是唯一的
+const MAX_LEN: usize = 90;
+
+fn polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    let mut chk = 1u32;
+    for v in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ v as u32;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut values: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|b| b & 31));
+    values
+}
+
+// 数据和校验和一起计算得到的余数，没有错误时为 0
+fn residue(hrp: &str, values: &[u8]) -> u32 {
+    polymod(hrp_expand(hrp).into_iter().chain(values.iter().copied())) ^ CONST
+}
+
+pub fn encode(hrp: &str, data: &[u8]) -> String {
+    let mut values = data.to_vec();
+    values.extend([0u8; CHECKSUM_LEN]);
+    let checksum = residue(hrp, &values);
+    for (i, v) in values[data.len()..].iter_mut().enumerate() {
+        *v = (checksum >> (5 * (CHECKSUM_LEN - 1 - i)) & 31) as u8;
+    }
+    let mut s = format!("{hrp}{SEPARATOR}");
+    s.extend(values.iter().map(|v| CHARSET[*v as usize] as char));
+    s
+}
+
+// 返回 hrp 和不含校验和的 5 bit 数据，全大写或全小写都可以
+pub fn decode(s: &str) -> Result<(String, Vec<u8>)> {
+    if s.len() > MAX_LEN {
+        return Err(anyhow!("too long"));
+    }
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(anyhow!("mixed upper and lower case"));
+    }
+    let s = s.to_ascii_lowercase();
+    let pos = s
+        .rfind(SEPARATOR)
+        .ok_or(anyhow!("missing separator {SEPARATOR}"))?;
+    let (hrp, data) = (&s[..pos], &s[pos + 1..]);
+    if hrp.is_empty() || data.len() < CHECKSUM_LEN {
+        return Err(anyhow!("too short"));
+    }
+    let mut values = data
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            CHARSET
+                .iter()
+                .position(|x| *x as char == c)
+                .map(|v| v as u8)
+                .ok_or(anyhow!(
+                    "invalid character {c:?} at position {}",
+                    pos + 2 + i
+                ))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    let diff = residue(hrp, &values);
+    if diff != 0 {
+        let positions: Vec<String> = locate_errors(hrp, &values, diff)
+            .iter()
+            .map(|i| (pos + 2 + i).to_string())
+            .collect();
+        return Err(match positions.len() {
+            0 => anyhow!("wrong checksum"),
+            _ => anyhow!(
+                "wrong checksum, likely typo at position {}",
+                positions.join(", ")
+            ),
+        });
+    }
+    values.truncate(values.len() - CHECKSUM_LEN);
+    Ok((hrp.to_string(), values))
+}
+
+// 余数对错误是线性的，先算出每个位置每种替换造成的余数差，再找一个或两个替换抵消余数
+fn locate_errors(hrp: &str, values: &[u8], target: u32) -> Vec<usize> {
+    let base = residue(hrp, &vec![0; values.len()]);
+    let mut single: HashMap<u32, usize> = HashMap::new();
+    for i in 0..values.len() {
+        let mut error = vec![0; values.len()];
+        for e in 1..32 {
+            error[i] = e;
+            single.insert(residue(hrp, &error) ^ base, i);
+        }
+    }
+    if let Some(i) = single.get(&target) {
+        return vec![*i];
+    }
+    for (diff, i) in &single {
+        match single.get(&(target ^ diff)) {
+            Some(j) if j > i => return vec![*i, *j],
+            _ => {}
+        }
+    }
+    vec![]
+}
+
+// 在 8 bit 和 5 bit 的分组之间转换
+pub fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0u32;
+    let mut out = vec![];
+    let max = (1u32 << to) - 1;
+    for v in data {
+        if (*v as u32) >> from != 0 {
+            return Err(anyhow!("invalid data value {v}"));
+        }
+        acc = acc << from | *v as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push((acc >> bits & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            out.push((acc << (to - bits) & max) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits) & max) != 0 {
+        return Err(anyhow!("invalid padding"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_bits, decode, encode};
+
+    #[test]
+    fn test_bech32m() {
+        // BIP-350 中合法的 Bech32m 字符串
+        for s in [
+            "A1LQFN3A",
+            "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+            "?1v759aa",
+        ] {
+            let (hrp, data) = decode(s).unwrap();
+            assert_eq!(encode(&hrp, &data), s.to_lowercase());
+        }
+        assert!(decode("A1LqFN3A")
+            .unwrap_err()
+            .to_string()
+            .contains("mixed"));
+        assert!(decode("1qzzfhee").is_err());
+
+        let data = convert_bits(&[0xde, 0xad, 0xbe, 0xef, 0x01], 8, 5, true).unwrap();
+        assert_eq!(
+            convert_bits(&data, 5, 8, false).unwrap(),
+            vec![0xde, 0xad, 0xbe, 0xef, 0x01]
+        );
+        let s = encode("sbtc", &data);
+
+        // 替换一个和两个字符都能找到位置 (从 1 开始数)
+        let typo = |s: &str, i: usize| {
+            let mut b = s.as_bytes().to_vec();
+            b[i - 1] = if b[i - 1] == b'q' { b'p' } else { b'q' };
+            String::from_utf8(b).unwrap()
+        };
+        let err = decode(&typo(&s, 8)).unwrap_err().to_string();
+        assert!(err.ends_with("position 8"), "{err}");
+        let err = decode(&typo(&typo(&s, 7), 12)).unwrap_err().to_string();
+        assert!(err.ends_with("position 7, 12"), "{err}");
+    }
+}
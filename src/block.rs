@@ -12,15 +12,22 @@ pub struct Block {
     pub prev_block_hash: String,        //前一个块的哈希，即父哈希
     pub hash: String,                   //当前块的哈希 (pre_block_hash+timestamp+data)
     pub nonce: u128,
+    #[serde(default)]
+    pub height: u64, //区块高度，创世块为0
 }
 
 impl Block {
-    pub fn new_block(prev_block_hash: String, transactions: Vec<Transaction>) -> Result<Self> {
+    pub fn new_block(
+        prev_block_hash: String,
+        height: u64,
+        transactions: Vec<Transaction>,
+    ) -> Result<Self> {
         let now = time::SystemTime::now().duration_since(UNIX_EPOCH)?;
         let mut block = Self {
             timestamp: now.as_millis(),
-            transactions: transactions,
-            prev_block_hash: prev_block_hash,
+            transactions,
+            prev_block_hash,
+            height,
             ..Default::default()
         };
 
@@ -55,8 +62,17 @@ impl Block {
             tx_hashes.extend(tx.id.as_bytes());
         }
 
-        let hash = sha256::digest(tx_hashes);
-        hash
+        sha256::digest(tx_hashes)
+    }
+
+    // 对所有交易的见证hash做承诺，签名被篡改会导致区块hash变化
+    pub fn hash_witnesses(&self) -> Result<String> {
+        let mut witness_hashes = vec![];
+        for tx in &self.transactions {
+            witness_hashes.extend(tx.witness_hash()?.into_bytes());
+        }
+
+        Ok(sha256::digest(witness_hashes))
     }
 
     pub fn get_hash(&self) -> String {
@@ -72,6 +88,34 @@ impl Block {
     }
 
     pub fn get_nonce(&self) -> u128 {
-        self.nonce.clone()
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash.clone(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            height: self.height,
+            transactions_hash: self.hash_transactions(),
+            witnesses_hash: self.hash_witnesses()?,
+        })
     }
 }
+
+// 区块数据被裁剪后保留的区块头，包含验证工作量证明需要的全部数据
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub prev_block_hash: String,
+    pub hash: String,
+    pub nonce: u128,
+    pub height: u64,
+    pub transactions_hash: String,
+    pub witnesses_hash: String,
+}
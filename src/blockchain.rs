@@ -1,19 +1,28 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{HashMap, HashSet, VecDeque},
     fs,
-    process::{self, Output},
-    str::from_utf8,
+    sync::Arc,
 };
 
 use crate::{
-    block::Block,
+    block::{Block, BlockHeader},
+    config::Config,
+    datadir::DataDir,
+    index,
+    params::ChainParams,
+    proof_of_work::ProofOfWork,
+    schema,
+    sigcache::SigCache,
+    store::{
+        self, Batch, ChainStore, MemoryStore, SledStore, ADDRINDEX, BLOCKS, HEADERS, PRUNE_HEIGHT,
+        TXINDEX, UNDO,
+    },
     transaction::{Transaction, TxOutput},
+    utxoset::{Coin, UTXOSet, UtxoSnapshot},
+    wallet::hash_pubkey,
 };
-use anyhow::{anyhow, Error, Result};
-use sled::{
-    transaction::{ConflictableTransactionError, TransactionError},
-    IVec,
-};
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
 use tracing::error;
 
 const GENESISCOINBASEDATA: &str = "GenesisCoinBaseData";
@@ -21,229 +30,986 @@ const GENESISCOINBASEDATA: &str = "GenesisCoinBaseData";
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     pub tip: String,
-    db: sled::Db,
+    store: Arc<dyn ChainStore>,
+    datadir: Option<DataDir>, // 内存中的链没有数据目录
+    config: Config,
+    sigcache: Arc<SigCache>,
 }
 
-const DB_FILE: &str = "btc_data";
-const BLOCKS: &str = "blocks";
-const LAST: &str = "last";
-
 impl Blockchain {
-    pub fn new_block_chain() -> Result<Self> {
-        if db_exists() == false {
+    pub fn new_block_chain(datadir: &DataDir) -> Result<Self> {
+        if !db_exists(datadir) {
             error!("No existing blockchian found, Create one first");
             return Err(anyhow!("No existing blockchian found, Create one first"));
         }
 
-        let db = sled::open(DB_FILE)?;
-        let bucket = db.open_tree(BLOCKS)?;
-        let tip = match bucket.get(LAST)? {
-            Some(iv) => from_utf8(iv.as_ref())?.into(),
-            None => return Err(anyhow!("Get last info, return None")),
-        };
+        let block_chain = Self::open_for_migration(datadir)?;
+        schema::check_version(block_chain.store.as_ref())?;
+        Ok(block_chain)
+    }
 
-        let block_chain = Self { tip, db };
+    // 只读打开，用于查询命令：读入最近一个写入命令完成时导出的检查点，不打开 sled 数据库，
+    // 可以和正在写入的进程同时运行，看不到它还没有完成的修改
+    pub fn open_read_only(datadir: &DataDir) -> Result<Self> {
+        if !db_exists(datadir) {
+            return Err(anyhow!("No existing blockchian found, Create one first"));
+        }
+        // 旧的数据目录还没有检查点，没有写入的进程时现在导出一个
+        if fs::metadata(datadir.checkpoint_path()).is_err() {
+            let _lock = datadir.lock().map_err(|_| {
+                anyhow!("The block database has no checkpoint yet, try again after the running command finishes")
+            })?;
+            Self::open_for_migration(datadir)?.write_checkpoint()?;
+        }
+
+        let store = MemoryStore::load_checkpoint(datadir.checkpoint_path())?;
+        let mut block_chain = Self::open(Arc::new(store), Config::load(datadir)?)?;
+        block_chain.datadir = Some(datadir.clone());
+        schema::check_version(block_chain.store.as_ref())?;
         Ok(block_chain)
     }
 
-    pub fn create_block_chain(address: String) -> Result<Self> {
-        if db_exists() {
+    // 不检查存储格式的版本，只用于升级
+    pub fn open_for_migration(datadir: &DataDir) -> Result<Self> {
+        if !db_exists(datadir) {
+            return Err(anyhow!("No existing blockchian found, Create one first"));
+        }
+
+        let store = SledStore::open(datadir.db_path())?;
+        let mut block_chain = Self::open(Arc::new(store), Config::load(datadir)?)?;
+        block_chain.datadir = Some(datadir.clone());
+        Ok(block_chain)
+    }
+
+    pub fn create_block_chain(address: String, datadir: &DataDir) -> Result<Self> {
+        if db_exists(datadir) {
+            error!("Blockchian already exist");
+            return Err(anyhow!("Blockchian already exist"));
+        }
+
+        let tx = Transaction::new_coin_base_tx(address, GENESISCOINBASEDATA.into())?;
+        Self::create_block_chain_from_genesis(new_genesis_block(tx)?, datadir)
+    }
+
+    // 使用已有的创世块创建链，例如从导出的文件中导入
+    pub fn create_block_chain_from_genesis(genesis: Block, datadir: &DataDir) -> Result<Self> {
+        if db_exists(datadir) {
             error!("Blockchian already exist");
             return Err(anyhow!("Blockchian already exist"));
         }
-        let db = sled::open(DB_FILE).unwrap();
 
-        let bucket = db.open_tree(BLOCKS).unwrap();
+        let config = Config::load(datadir)?;
+        let store = SledStore::open(datadir.db_path())?;
+        let mut block_chain = Self::create_with_genesis(Arc::new(store), genesis, config)?;
+        block_chain.datadir = Some(datadir.clone());
+        Ok(block_chain)
+    }
+
+    // 从 UTXO 快照创建链，见 create_from_snapshot
+    pub fn create_block_chain_from_snapshot(
+        snapshot: &UtxoSnapshot,
+        datadir: &DataDir,
+        trust_config: bool,
+    ) -> Result<Self> {
+        if db_exists(datadir) {
+            return Err(anyhow!("Blockchian already exist"));
+        }
+
+        let config = Config::load(datadir)?;
+        let store = SledStore::open(datadir.db_path())?;
+        let mut block_chain =
+            Self::create_from_snapshot(Arc::new(store), snapshot, config, trust_config)?;
+        block_chain.datadir = Some(datadir.clone());
+        Ok(block_chain)
+    }
+
+    // 打开任意存储中已经存在的链
+    pub fn open(store: Arc<dyn ChainStore>, config: Config) -> Result<Self> {
+        config.validate()?;
+        let tip = match store.get_tip()? {
+            Some(tip) => tip,
+            None => return Err(anyhow!("Get last info, return None")),
+        };
+
+        Ok(Self {
+            tip,
+            store,
+            datadir: None,
+            config,
+            sigcache: Arc::default(),
+        })
+    }
+
+    // 在空的存储中创建创世块
+    pub fn create(store: Arc<dyn ChainStore>, address: String, config: Config) -> Result<Self> {
+        if store.get_tip()?.is_some() {
+            return Err(anyhow!("Blockchian already exist"));
+        }
 
         let tx = Transaction::new_coin_base_tx(address, GENESISCOINBASEDATA.into())?;
-        let genesis = new_genesis_block(tx)?;
+        Self::create_with_genesis(store, new_genesis_block(tx)?, config)
+    }
 
-        let genesis_json = genesis.serialize()?;
-        bucket.insert(genesis.get_hash().as_str(), genesis_json.as_str())?;
-        bucket.insert(LAST, genesis.get_hash().as_str())?;
+    pub fn create_with_genesis(
+        store: Arc<dyn ChainStore>,
+        genesis: Block,
+        config: Config,
+    ) -> Result<Self> {
+        config.validate()?;
+        if store.get_tip()?.is_some() {
+            return Err(anyhow!("Blockchian already exist"));
+        }
+        // 创世块只包含一笔 coinbase 交易
+        let coinbase_only = genesis.transactions.len() == 1 && genesis.transactions[0].is_coinbase();
+        if !genesis.get_prehash().is_empty() || genesis.get_height() != 0 || !coinbase_only {
+            return Err(anyhow!("Block {} is not a genesis block", genesis.get_hash()));
+        }
+        check_proof_of_work(&genesis)?;
+        if genesis.transactions[0].txid()? != genesis.transactions[0].id {
+            return Err(anyhow!("Genesis coinbase has a wrong id"));
+        }
 
         let block_chain = Self {
             tip: genesis.get_hash(),
-            db,
+            store,
+            datadir: None,
+            config,
+            sigcache: Arc::default(),
         };
+        // 创世块和它的 UTXO 一起写入，不会出现有区块没有链状态的情况
+        let mut batch = Batch::default();
+        schema::set_version(&mut batch, schema::SCHEMA_VERSION);
+        batch.put_block(&genesis)?;
+        batch.set_tip(genesis.get_hash().as_str());
+        batch.set_height(0, &genesis.get_hash());
+        UTXOSet::new(block_chain.clone()).connect_block(&mut batch, &genesis)?;
+        if block_chain.config.txindex {
+            index::index_block_txs(&mut batch, &genesis)?;
+        }
+        if block_chain.config.addrindex {
+            let store = block_chain.store.as_ref();
+            index::connect_block_addresses(store, &mut batch, &genesis, &HashMap::new())?;
+        }
+        block_chain.store.apply(batch)?;
         Ok(block_chain)
     }
 }
 
 impl Blockchain {
+    // 没有区块数据的节点从快照开始：只保存快照区块及之前的区块头，这些区块视为已经被裁剪，
+    // 之后的区块用 import_block 接入，trust_config 见 ChainParams::new
+    pub fn create_from_snapshot(
+        store: Arc<dyn ChainStore>,
+        snapshot: &UtxoSnapshot,
+        config: Config,
+        trust_config: bool,
+    ) -> Result<Self> {
+        config.validate()?;
+        if store.get_tip()?.is_some() {
+            return Err(anyhow!("Blockchian already exist"));
+        }
+        // 区块头相连并且从创世块开始，第一个区块头确定快照所在的网络
+        snapshot.check_headers()?;
+        snapshot.check(&ChainParams::new(&snapshot.headers[0].hash, &config, trust_config))?;
+
+        let mut batch = Batch::default();
+        schema::set_version(&mut batch, schema::SCHEMA_VERSION);
+        for header in snapshot.headers.iter() {
+            batch.insert(HEADERS, &header.hash, serde_json::to_string(header)?);
+            batch.set_height(header.height, &header.hash);
+        }
+        batch.insert(HEADERS, PRUNE_HEIGHT, snapshot.height.to_string());
+        batch.set_tip(&snapshot.block_hash);
+        for entry in snapshot.coins.iter() {
+            batch.put_utxo(&entry.txid, entry.vout, &entry.coin)?;
+        }
+        store.apply(batch)?;
+
+        Ok(Self {
+            tip: snapshot.block_hash.clone(),
+            store,
+            datadir: None,
+            config,
+            sigcache: Arc::default(),
+        })
+    }
+
     pub fn mine_block(&mut self, txes: Vec<Transaction>) -> Result<Block> {
+        let height = self.get_best_height()? + 1;
+        if !self.verify_block_transactions(&txes, height)? {
+            return Err(anyhow!("Verity tx failed"));
+        }
+        let prev_txs = self.prev_transactions(&txes)?;
         for tx in txes.iter() {
-            if !self.verify_transaction(tx)? {
-                return Err(anyhow!("Verity tx failed"));
-            }
+            check_values(tx, &prev_txs)?;
         }
 
-        let db = self.db.open_tree(BLOCKS)?;
+        let block = Block::new_block(self.tip.clone(), height, txes)?;
 
-        let res: Result<Block, TransactionError<anyhow::Error>> =
-            db.transaction(|tx_db: &sled::transaction::TransactionalTree| {
-                match tx_db.get(LAST)? {
-                    Some(iv) => match from_utf8(iv.as_ref()) {
-                        Ok(_) => {
-                            let block = Block::new_block(self.tip.clone(), txes.clone())
-                                .map_err(|e| ConflictableTransactionError::Abort(anyhow!(e)))?;
+        let batch = self.connect_batch(&block)?;
+        self.store.apply(batch)?;
 
-                            tx_db.insert(
-                                block.get_hash().as_str(),
-                                block
-                                    .serialize()
-                                    .map_err(|e| ConflictableTransactionError::Abort(anyhow!(e)))?
-                                    .as_str(),
-                            )?;
+        self.tip = block.get_hash();
+        self.prune_after_connect();
+        Ok(block)
+    }
 
-                            tx_db.insert(LAST, block.get_hash().as_str())?;
-                            Ok(block)
-                        }
-                        Err(e) => Err(ConflictableTransactionError::Abort(anyhow!(e))),
-                    },
-                    None => Err(ConflictableTransactionError::Abort(anyhow!(
-                        "Get key=={}, return None",
-                        LAST
-                    ))),
+    // 接入其他节点产生的区块
+    pub fn import_block(&mut self, block: &Block) -> Result<()> {
+        if block.get_prehash() != self.tip {
+            return Err(anyhow!(
+                "Block {} at height {} does not extend the tip {}",
+                block.get_hash(),
+                block.get_height(),
+                self.tip
+            ));
+        }
+        let height = self.get_best_height()? + 1;
+        if block.get_height() != height {
+            return Err(anyhow!("Block {} has wrong height {}", block.get_hash(), block.get_height()));
+        }
+        check_proof_of_work(block)?;
+        // 只有创世块产生新的币
+        if block.transactions.iter().any(|tx| tx.is_coinbase()) {
+            return Err(anyhow!("Block {} contains a coinbase transaction", block.get_hash()));
+        }
+        if !self.verify_block_transactions(&block.transactions, height)? {
+            return Err(anyhow!("Verity tx failed in block {}", block.get_hash()));
+        }
+        let prev_txs = self.prev_transactions(&block.transactions)?;
+        for tx in block.transactions.iter() {
+            check_values(tx, &prev_txs)?;
+        }
+
+        let batch = self.connect_batch(block)?;
+        self.store.apply(batch)?;
+
+        self.tip = block.get_hash();
+        self.prune_after_connect();
+        Ok(())
+    }
+
+    // 新区块成为 tip 需要的所有修改，区块、UTXO 集合、回滚数据和索引在同一个批次中写入，
+    // 写入时 tip 必须还是 self.tip
+    fn connect_batch(&self, block: &Block) -> Result<Batch> {
+        let mut batch = Batch::default();
+        batch.expect_tip(&self.tip);
+        batch.put_block(block)?;
+        batch.set_tip(block.get_hash().as_str());
+        batch.set_height(block.get_height(), &block.get_hash());
+        UTXOSet::new(self.clone()).connect_block(&mut batch, block)?;
+        // 索引落后时不再维护，等待 reindex 重建
+        if self.txindex_synced()? {
+            index::index_block_txs(&mut batch, block)?;
+        }
+        if self.addrindex_synced()? {
+            let prev_txs = self.prev_transactions(&block.transactions)?;
+            index::connect_block_addresses(self.store.as_ref(), &mut batch, block, &prev_txs)?;
+        }
+        Ok(batch)
+    }
+
+    // 从所有的区块中计算未花费的输出，用于重建和检查 UTXO 集合
+    pub fn find_utxo(&self) -> Result<HashMap<(String, isize), Coin>> {
+        self.find_utxo_at(&self.tip)
+    }
+
+    // 计算到 hash 对应的区块为止的未花费输出
+    pub fn find_utxo_at(&self, hash: &str) -> Result<HashMap<(String, isize), Coin>> {
+        self.require_unpruned("recomputing the UTXO set")?;
+        let mut utxo = HashMap::new();
+        // 从最新的区块往前遍历，输出被花费的记录总是先于输出本身出现
+        let mut spent_txos: HashSet<(String, isize)> = HashSet::new();
+
+        let bci = BlockChainIter {
+            hash: hash.into(),
+            store: self.store.clone(),
+        };
+        for block in bci {
+            let block = block?;
+            for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                for vin in tx.vin.iter() {
+                    spent_txos.insert((vin.txid.clone(), vin.vout));
                 }
-            });
+            }
 
-        match res {
-            Ok(v) => {
-                self.tip = v.get_hash();
-                Ok(v)
+            for tx in block.transactions.iter() {
+                for (index, out) in tx.vout.iter().enumerate() {
+                    let outpoint = (tx.id.clone(), index as isize);
+                    if !spent_txos.contains(&outpoint) {
+                        let coin = Coin::new(out.clone(), block.get_height(), tx.is_coinbase());
+                        utxo.insert(outpoint, coin);
+                    }
+                }
             }
-            Err(e) => Err(anyhow!(e)),
         }
+
+        Ok(utxo)
     }
 
-    // 暂时从所有的区块中获取未花费的交易
-    pub fn find_utxo(&self) -> Result<HashMap<String, Vec<TxOutput>>> {
-        let mut utxo: HashMap<String, Vec<TxOutput>> = HashMap::new();
-        // vec中存储的是一笔交易中的已经花费的输出的索引
-        // 使用vec 是因为，这笔交易中包含，不确定几个人的交易输出
-        let mut spent_txos: HashMap<String, Vec<isize>> = HashMap::new();
+    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
+        if self.txindex_synced()? {
+            let location = index::get_tx_location(self.store.as_ref(), id)?
+                .ok_or(anyhow!("Do not cantains this tx"))?;
+            if self.store.is_pruned(&location.block_hash)? {
+                return Err(anyhow!(
+                    "Transaction {id} is in block {} which has been pruned",
+                    location.block_hash
+                ));
+            }
+            let block = self
+                .store
+                .get_block(&location.block_hash)?
+                .ok_or(anyhow!("Get block {}, return None", location.block_hash))?;
+            return match block.transactions.into_iter().nth(location.position) {
+                Some(tx) if tx.id == id => Ok(tx),
+                _ => Err(anyhow!("Transaction index is inconsistent for {id}")),
+            };
+        }
 
-        let mut bci = self.iterator();
-        loop {
-            let block = bci.next()?;
-            for tx in block.transactions {
-                'Output:
-                // 先遍历交易中的所有输出
-                for (index, out) in tx.vout.iter().enumerate() {
-                    // 已花费的map中，包含此交易
-                    if spent_txos.contains_key(tx.id.as_str()) {
-                        for spent_out in &spent_txos[tx.id.as_str()] {
-                            // 就判断是否包含本条输出,如果包含，说明已经花费了，继续下一条判断 
-                            if index as isize == *spent_out {
-                                continue 'Output;
-                            }
-                        }
-                    }   
+        for header in self.headers() {
+            let header = header?;
+            if self.store.is_pruned(&header.hash)? {
+                return Err(anyhow!("Transaction {id} not found in the unpruned blocks"));
+            }
+            let block = get_block(self.store.as_ref(), &header.hash)?;
+            if let Some(tx) = block.transactions.into_iter().find(|tx| tx.id == id) {
+                return Ok(tx);
+            }
+        }
+        Err(anyhow!("Do not cantains this tx"))
+    }
 
-                let txs =  utxo.entry(tx.id.clone()).or_insert(vec![]);
-                txs.push(out.clone());
+    // 查找一个输出，所在的区块被裁剪时从 UTXO 集合中查找，此时只能找到未花费的输出
+    pub fn find_output(&self, txid: &str, vout: isize) -> Result<TxOutput> {
+        match self.find_transaction(txid) {
+            Ok(tx) => tx
+                .vout
+                .into_iter()
+                .nth(vout as usize)
+                .ok_or(anyhow!("Output {vout} not found in {txid}")),
+            Err(e) => match self.store.get_utxo(txid, vout)? {
+                Some(coin) if self.store.get_prune_height()?.is_some() => Ok(coin.output),
+                _ => Err(e),
+            },
+        }
+    }
 
-                }
+    pub fn sign_transaction(&self, tx: &mut Transaction, privkey:  &[u8]) -> Result<()> {
+        let prev_txs = self.prev_transactions(std::slice::from_ref(tx))?;
+        tx.sign(privkey, prev_txs)
+    }
 
-                if !tx.is_coinbase() {
-                    for i in tx.vin {
-                        // if i.uses_key(pubkey_hash) {
-                            // println!("------------i.txid:{:?}------------", i.txid);
-                            let vec_txos: &mut Vec<isize> = spent_txos.entry(i.txid).or_insert(vec![]);
-                            vec_txos.push(i.vout);
-                        // }
+    pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
+        let prev_txs = self.prev_transactions(std::slice::from_ref(tx))?;
+        tx.verify(prev_txs, self.get_best_height()? + 1)
+    }
+
+    // 先收集区块中所有输入引用的交易，再把签名验证分发到线程池中并行执行，
+    // 交易池中已经验证过的签名不再重复验证
+    pub fn verify_block_transactions(&self, txes: &[Transaction], height: u64) -> Result<bool> {
+        self.sigcache.load(self.store.as_ref())?;
+        let prev_txs = self.prev_transactions(txes)?;
+        verify_transactions(txes, &prev_txs, height, &self.sigcache)
+    }
+
+    // 一组交易的所有输入引用的交易
+    pub fn prev_transactions(&self, txes: &[Transaction]) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        let mut pruned: HashMap<String, Transaction> = HashMap::new();
+        for tx in txes.iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in tx.vin.iter() {
+                if prev_txs.contains_key(&vin.txid) {
+                    continue;
+                }
+                match self.find_transaction(&vin.txid) {
+                    Ok(prev_tx) => {
+                        prev_txs.insert(vin.txid.clone(), prev_tx);
+                    }
+                    // 交易所在的区块被裁剪时，用 UTXO 集合中的输出拼出这笔交易，只有被引用的位置有效
+                    Err(e) => {
+                        let coin = match self.store.get_utxo(&vin.txid, vin.vout)? {
+                            Some(coin) if self.store.get_prune_height()?.is_some() => coin,
+                            _ => return Err(e),
+                        };
+                        let prev_tx = pruned.entry(vin.txid.clone()).or_insert(Transaction {
+                            id: vin.txid.clone(),
+                            ..Default::default()
+                        });
+                        if prev_tx.vout.len() <= vin.vout as usize {
+                            prev_tx.vout.resize(vin.vout as usize + 1, TxOutput::default());
+                        }
+                        prev_tx.vout[vin.vout as usize] = coin.output;
                     }
                 }
             }
+        }
+        prev_txs.extend(pruned);
+        Ok(prev_txs)
+    }
 
-            if block.prev_block_hash.len() == 0 {
-                break;
+
+    // 开启了交易索引，并且索引已经同步到当前的最新区块
+    fn txindex_synced(&self) -> Result<bool> {
+        if !self.config.txindex {
+            return Ok(false);
+        }
+        Ok(index::txindex_best(self.store.as_ref())?.as_deref() == Some(self.tip.as_str()))
+    }
+
+    // 重新建立交易索引，关闭索引时清空旧的数据
+    pub fn reindex_txindex(&self) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.clear(TXINDEX);
+        if self.config.txindex {
+            for block in self.blocks_from_genesis()? {
+                index::index_block_txs(&mut batch, &block?)?;
             }
+            index::set_txindex_best(&mut batch, &self.tip);
         }
+        self.store.apply(batch)
+    }
 
-        Ok(utxo)
+    fn addrindex_synced(&self) -> Result<bool> {
+        if !self.config.addrindex {
+            return Ok(false);
+        }
+        Ok(index::addrindex_best(self.store.as_ref())?.as_deref() == Some(self.tip.as_str()))
     }
 
-    
+    // 从创世块开始重新建立地址索引，输入引用的交易一定在之前的区块中
+    pub fn reindex_addrindex(&self) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.clear(ADDRINDEX);
+        if self.config.addrindex {
+            let mut txs: HashMap<String, Transaction> = HashMap::new();
+            let mut history: HashMap<String, Vec<index::AddrTxEntry>> = HashMap::new();
+            for block in self.blocks_from_genesis()? {
+                let block = block?;
+                for (pubkey_hash, entries) in index::address_entries(&block, &txs)? {
+                    history.entry(pubkey_hash).or_default().extend(entries);
+                }
+                for tx in block.transactions.iter() {
+                    txs.insert(tx.id.clone(), tx.clone());
+                }
+            }
+            for (pubkey_hash, entries) in history {
+                batch.insert(ADDRINDEX, &pubkey_hash, serde_json::to_string(&entries)?);
+            }
+            index::set_addrindex_best(&mut batch, &self.tip);
+        }
+        self.store.apply(batch)
+    }
 
-    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
+    // 地址相关的所有交易记录，按区块高度从低到高排列
+    pub fn address_history(&self, pubkey_hash: &str) -> Result<Vec<index::AddrTxEntry>> {
+        if !self.addrindex_synced()? {
+            return Err(anyhow!(
+                "Address index is not available, set addrindex in config.json and run reindex"
+            ));
+        }
+        index::get_address_history(self.store.as_ref(), pubkey_hash)
+    }
 
-        let mut bci = self.iterator();
-        loop {
-            let block: Block = bci.next()?;
-            for tx in block.transactions {
-                if tx.id.eq(id) {
-                    return Ok(tx);
+    // 链上出现过的所有 pubkey hash，用于恢复钱包时查找使用过的地址，
+    // 被裁剪的区块只能从 UTXO 集合中找到未花费的输出
+    pub fn used_pubkey_hashes(&self) -> Result<HashSet<String>> {
+        let mut used = HashSet::new();
+        for header in self.headers() {
+            let Some(block) = self.store.get_block(&header?.hash)? else {
+                continue;
+            };
+            for tx in block.transactions.iter() {
+                if !tx.is_coinbase() {
+                    for vin in tx.vin.iter() {
+                        used.insert(hex::encode(hash_pubkey(&vin.pubkey)));
+                    }
+                }
+                for out in tx.vout.iter() {
+                    used.extend(output_pubkey_hashes(out));
                 }
             }
+        }
+        for (_, coin) in self.store.utxos()? {
+            used.extend(output_pubkey_hashes(&coin.output));
+        }
+        Ok(used)
+    }
 
-            if block.prev_block_hash.is_empty() {
-                return Err(anyhow!("Do not cantains this tx"));
+    // 按 高度 或 hash 查找当前链上的区块头，通过高度索引查找，不需要遍历链
+    pub fn find_block(&self, target: &str) -> Result<BlockHeader> {
+        let not_found = || anyhow!("Block {target} is not in the chain");
+        let hash = match target.parse::<u64>() {
+            Ok(height) => self.store.get_hash_at(height)?.ok_or_else(not_found)?,
+            Err(_) => target.to_string(),
+        };
+        let header = self.store.get_header(&hash)?.ok_or_else(not_found)?;
+        // 已经被回滚的区块还保存在存储中，但高度索引指向当前链上的区块
+        if self.store.get_hash_at(header.height)?.as_deref() != Some(hash.as_str()) {
+            return Err(not_found());
+        }
+        Ok(header)
+    }
+
+    // 回滚到 target，tip、UTXO 集合和索引在同一个批次中修改，返回被回滚的区块；
+    // 区块数据保留在存储中，回滚之后还可以重新导入
+    pub fn rollback(&mut self, target: &str) -> Result<Vec<Block>> {
+        let target = self.find_block(target)?;
+        if let Some(prune_height) = self.store.get_prune_height()? {
+            if target.height <= prune_height {
+                return Err(anyhow!(
+                    "Blocks up to height {prune_height} have been pruned, can not roll back to height {}",
+                    target.height
+                ));
             }
         }
+        let utxoset = UTXOSet::new(self.clone());
+
+        let mut blocks = vec![];
+        let mut batch = Batch::default();
+        for header in self.headers() {
+            let header = header?;
+            if header.hash == target.hash {
+                break;
+            }
+            let block = get_block(self.store.as_ref(), &header.hash)?;
+            utxoset.disconnect_block(&mut batch, &block)?;
+            batch.remove_height(header.height);
+            if self.txindex_synced()? {
+                index::unindex_block_txs(&mut batch, &block);
+            }
+            blocks.push(block);
+        }
+        if self.addrindex_synced()? {
+            index::disconnect_block_addresses(self.store.as_ref(), &mut batch, &blocks)?;
+        }
+        batch.expect_tip(&self.tip);
+        batch.set_tip(target.hash.as_str());
+        self.store.apply(batch)?;
 
+        self.tip = target.hash;
+        Ok(blocks)
     }
 
-    pub fn sign_transaction(&self, tx: &mut Transaction, privkey:  &[u8]) -> Result<()> {
-        let prev_txs: Result<HashMap<String, Transaction>, _> = tx.vin.iter().map(|v| -> Result<(String, Transaction), anyhow::Error> {
-            let prev_tx = self.find_transaction(&v.txid)?;
-            Ok((prev_tx.id.clone(), prev_tx))
-        }).collect();
+    // 区块已经写入，裁剪失败不影响这个区块，下一个区块写入时会再次裁剪
+    fn prune_after_connect(&self) {
+        if let Err(e) = self.prune() {
+            error!("Prune failed after block {}: {e}", self.tip);
+        }
+    }
+
+    // 删除距离 tip 超过配置深度的区块数据，只保留区块头，返回裁剪的区块数
+    pub fn prune(&self) -> Result<usize> {
+        let Some(depth) = self.config.prune else {
+            return Ok(0);
+        };
+        let best = self.get_best_height()?;
+        if best < depth {
+            return Ok(0);
+        }
+        let prune_height = best - depth;
+        let pruned_before = self.store.get_prune_height()?;
+        if pruned_before >= Some(prune_height) {
+            return Ok(0);
+        }
 
+        let mut batch = Batch::default();
+        let mut pruned = 0;
+        for header in self.headers() {
+            let header = header?;
+            if Some(header.height) == pruned_before {
+                break;
+            }
+            if header.height <= prune_height {
+                batch.insert(HEADERS, &header.hash, serde_json::to_string(&header)?);
+                batch.remove(BLOCKS, &header.hash);
+                batch.remove(UNDO, &header.hash);
+                pruned += 1;
+            }
+        }
+        batch.insert(HEADERS, PRUNE_HEIGHT, prune_height.to_string());
+        self.store.apply(batch)?;
+        Ok(pruned)
+    }
 
-        tx.sign(privkey, prev_txs?)
+    // 需要所有区块数据的操作在裁剪过的链上直接返回错误
+    fn require_unpruned(&self, operation: &str) -> Result<()> {
+        match self.store.get_prune_height()? {
+            Some(height) => Err(anyhow!(
+                "Blocks up to height {height} have been pruned, {operation} needs all block data"
+            )),
+            None => Ok(()),
+        }
     }
 
-    pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
-        let prev_txs: Result<HashMap<String, Transaction>, _> = tx.vin.iter().map(|v| -> Result<(String, Transaction), anyhow::Error> {
-            let prev_tx = self.find_transaction(&v.txid)?;
-            Ok((prev_tx.id.clone(), prev_tx))
-        }).collect();
+    // 从创世块到 tip 的所有区块
+    pub fn blocks_from_genesis(&self) -> Result<BlockRangeIter> {
+        self.require_unpruned("reading the whole chain")?;
+        self.blocks_in_range(None, None)
+    }
 
-        tx.verify(prev_txs?)
+    // 高度在 [from, to] 内的区块，从低到高遍历，没有指定的一端不限制
+    pub fn blocks_in_range(&self, from: Option<u64>, to: Option<u64>) -> Result<BlockRangeIter> {
+        let hashes = self
+            .headers_in_range(from, to)?
+            .into_iter()
+            .map(|header| header.hash)
+            .collect();
+        Ok(BlockRangeIter {
+            hashes,
+            store: self.store.clone(),
+        })
     }
 
+    // 高度在 [from, to] 内的区块头，从低到高排列，被裁剪的区块也有区块头
+    pub fn headers_in_range(&self, from: Option<u64>, to: Option<u64>) -> Result<Vec<BlockHeader>> {
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(anyhow!("Invalid height range {from}..={to}"));
+            }
+        }
+        let best = self.get_best_height()?;
+        let to = to.map_or(best, |to| to.min(best));
+        let mut headers = vec![];
+        for height in from.unwrap_or(0)..=to {
+            let hash = self
+                .store
+                .get_hash_at(height)?
+                .ok_or(anyhow!("Height index has no block at height {height}"))?;
+            let header = self
+                .store
+                .get_header(&hash)?
+                .ok_or(anyhow!("Block {hash} at height {height} is missing"))?;
+            headers.push(header);
+        }
+        Ok(headers)
+    }
 
+    pub fn get_best_height(&self) -> Result<u64> {
+        match self.headers().next() {
+            Some(header) => Ok(header?.height),
+            None => Err(anyhow!("Get block, return None")),
+        }
+    }
+
+    // 从 tip 到创世块遍历区块
     pub fn iterator(&self) -> BlockChainIter {
         BlockChainIter {
             hash: self.tip.clone(),
-            db: self.db.clone(),
+            store: self.store.clone(),
         }
     }
 
-    pub fn get_db(&self) -> sled::Db{
-        self.db.clone()
+    // 从 tip 到创世块遍历区块头，可以越过被裁剪的区块
+    pub fn headers(&self) -> HeaderIter {
+        HeaderIter {
+            hash: self.tip.clone(),
+            store: self.store.clone(),
+        }
+    }
+
+    // 写入命令完成后导出检查点，调用者需要持有数据目录的锁
+    pub fn write_checkpoint(&self) -> Result<()> {
+        store::write_checkpoint(self.store.as_ref(), &self.get_datadir()?.checkpoint_path())
+    }
+
+    pub fn get_store(&self) -> Arc<dyn ChainStore> {
+        self.store.clone()
     }
 
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_sigcache(&self) -> &SigCache {
+        &self.sigcache
+    }
 
+    pub fn get_datadir(&self) -> Result<&DataDir> {
+        self.datadir
+            .as_ref()
+            .ok_or(anyhow!("In-memory blockchain has no data directory"))
+    }
 }
 
 pub fn new_genesis_block(coinbase: Transaction) -> Result<Block> {
-    Block::new_block("".into(), vec![coinbase])
+    Block::new_block("".into(), 0, vec![coinbase])
 }
 
-pub fn db_exists() -> bool {
-    fs::metadata(DB_FILE).is_ok()
+// 在线程池中并行验证一组交易的签名，prev_txs 需要包含所有输入引用的交易
+pub fn verify_transactions(
+    txes: &[Transaction],
+    prev_txs: &HashMap<String, Transaction>,
+    height: u64,
+    sigcache: &SigCache,
+) -> Result<bool> {
+    let mut checks = vec![];
+    for tx in txes {
+        match tx.signature_checks(prev_txs, height)? {
+            Some(tx_checks) => checks.extend(tx_checks),
+            None => return Ok(false),
+        }
+    }
+
+    Ok(checks.par_iter().all(|c| sigcache.verify(c)))
+}
+
+// 输出金额不能为负，输入不能重复，输出总额不能超过输入总额，否则交易凭空产生了币
+pub fn check_values(tx: &Transaction, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+    if tx.vout.iter().any(|out| out.value < 0) {
+        return Err(anyhow!("Transaction {} has a negative output", tx.id));
+    }
+    if tx.is_coinbase() {
+        return Ok(());
+    }
+    let mut inputs = HashSet::new();
+    if !tx.vin.iter().all(|vin| inputs.insert((&vin.txid, vin.vout))) {
+        return Err(anyhow!("Transaction {} spends the same output twice", tx.id));
+    }
+    let fee = tx.fee(prev_txs)?;
+    if fee < 0 {
+        return Err(anyhow!("Transaction {} spends {} more than its inputs", tx.id, -fee));
+    }
+    Ok(())
+}
+
+// 区块的hash和 nonce 对应，并且满足难度要求
+pub fn check_proof_of_work(block: &Block) -> Result<()> {
+    let pow = ProofOfWork::new_proof_of_work(block.clone());
+    if pow.hash()? != block.get_hash() || !pow.validate()? {
+        return Err(anyhow!("Block {} has invalid proof of work", block.get_hash()));
+    }
+    Ok(())
+}
+
+pub fn output_pubkey_hashes(out: &TxOutput) -> Vec<String> {
+    match &out.htlc {
+        Some(htlc) => vec![
+            htlc.receiver_pubkey_hash.clone(),
+            htlc.refund_pubkey_hash.clone(),
+        ],
+        None => vec![out.pubkey_hash.clone()],
+    }
+}
+
+pub fn db_exists(datadir: &DataDir) -> bool {
+    fs::metadata(datadir.db_path()).is_ok()
 }
 
 pub struct BlockChainIter {
     hash: String,
-    db: sled::Db,
+    store: Arc<dyn ChainStore>,
 }
 
-impl BlockChainIter {
-    pub fn next(&mut self) -> Result<Block> {
-        let db = self.db.open_tree(BLOCKS)?;
-        match db.get(self.hash.clone())? {
-            Some(iv) => {
-                let data = from_utf8(iv.as_ref())?;
-                let bc = Block::deserialize(data)?;
-                self.hash = bc.get_prehash();
-                Ok(bc)
-            }
+// 创世块的 prehash 为空，遍历到创世块或者出错后结束
+impl Iterator for BlockChainIter {
+    type Item = Result<Block>;
 
-            None => Err(anyhow!("Get block, return None")),
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.hash.is_empty() {
+            return None;
+        }
+        let block = get_block(self.store.as_ref(), &self.hash);
+        self.hash = match &block {
+            Ok(block) => block.get_prehash(),
+            Err(_) => String::new(),
+        };
+        Some(block)
+    }
+}
+
+pub struct HeaderIter {
+    hash: String,
+    store: Arc<dyn ChainStore>,
+}
+
+impl Iterator for HeaderIter {
+    type Item = Result<BlockHeader>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.hash.is_empty() {
+            return None;
         }
+        let header = match self.store.get_header(&self.hash) {
+            Ok(Some(header)) => Ok(header),
+            Ok(None) => Err(anyhow!("Get block {}, return None", self.hash)),
+            Err(e) => Err(e),
+        };
+        self.hash = match &header {
+            Ok(header) => header.prev_block_hash.clone(),
+            Err(_) => String::new(),
+        };
+        Some(header)
+    }
+}
+
+// 只保存区块hash，区块在遍历到时才读取
+pub struct BlockRangeIter {
+    hashes: VecDeque<String>,
+    store: Arc<dyn ChainStore>,
+}
+
+impl Iterator for BlockRangeIter {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let hash = self.hashes.pop_front()?;
+        Some(get_block(self.store.as_ref(), &hash))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.hashes.len(), Some(self.hashes.len()))
+    }
+}
+
+impl DoubleEndedIterator for BlockRangeIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let hash = self.hashes.pop_back()?;
+        Some(get_block(self.store.as_ref(), &hash))
+    }
+}
+
+impl ExactSizeIterator for BlockRangeIter {}
+
+// 区块数据被裁剪时返回明确的错误
+fn get_block(store: &dyn ChainStore, hash: &str) -> Result<Block> {
+    match store.get_block(hash)? {
+        Some(block) => Ok(block),
+        None if store.is_pruned(hash)? => Err(anyhow!("Block {hash} has been pruned")),
+        None => Err(anyhow!("Get block, return None")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::{
+        block::Block,
+        config::Config,
+        datadir::DataDir,
+        index::{self, Direction},
+        testutil::{new_chain, spend, spend_to_self},
+        transaction::TxOutput,
+        wallet::{hash_pubkey, Wallet},
+    };
+
+    use super::Blockchain;
+
+    #[test]
+    fn test_find_transaction_with_txindex() {
+        let config = Config {
+            txindex: true,
+            ..Default::default()
+        };
+        let (wallet, store, mut bc, coinbase) = new_chain(config);
+
+        let tx = spend_to_self(&wallet, &coinbase, 0, vec![50]);
+        let block = bc.mine_block(vec![tx.clone()]).unwrap();
+
+        let location = index::get_tx_location(store.as_ref(), &tx.id).unwrap().unwrap();
+        assert_eq!(location.block_hash, block.get_hash());
+        assert_eq!(bc.find_transaction(&tx.id).unwrap().id, tx.id);
+        assert_eq!(bc.find_transaction(&coinbase.id).unwrap().id, coinbase.id);
+        assert!(bc.find_transaction(&"0".repeat(64)).is_err());
+
+        // 不使用索引时结果一致
+        let scan = Blockchain::open(store, Config::default()).unwrap();
+        assert_eq!(scan.find_transaction(&tx.id).unwrap().id, tx.id);
+    }
+
+    #[test]
+    fn test_address_history() {
+        let bob = Wallet::new_wallet();
+        let config = Config {
+            addrindex: true,
+            ..Default::default()
+        };
+        let (alice, store, mut bc, coinbase) = new_chain(config);
+
+        let outputs = vec![
+            TxOutput::new_tx_output(20, &bob.address()),
+            TxOutput::new_tx_output(30, &alice.address()),
+        ];
+        let tx = spend(&alice, &coinbase, 0, outputs);
+        bc.mine_block(vec![tx.clone()]).unwrap();
+
+        let alice_hash = hex::encode(hash_pubkey(&alice.public_key));
+        let bob_hash = hex::encode(hash_pubkey(&bob.public_key));
+        let history = bc.address_history(&alice_hash).unwrap();
+        let summary: Vec<_> = history.iter().map(|e| (e.height, e.direction, e.amount)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, Direction::Received, 50),
+                (1, Direction::Sent, 50),
+                (1, Direction::Received, 30)
+            ]
+        );
+        let bob_history = bc.address_history(&bob_hash).unwrap();
+        assert_eq!(bob_history.len(), 1);
+        assert_eq!(bob_history[0].txid, tx.id);
+
+        // 重建的索引和增量维护的一致
+        bc.reindex_addrindex().unwrap();
+        assert_eq!(bc.address_history(&alice_hash).unwrap(), history);
+
+        // 没有开启索引时拒绝查询
+        let plain = Blockchain::open(store, Config::default()).unwrap();
+        assert!(plain.address_history(&alice_hash).is_err());
+    }
+
+    #[test]
+    fn test_iterate_in_both_directions() {
+        let (_, _, mut bc, _) = new_chain(Config::default());
+        for _ in 0..3 {
+            bc.mine_block(vec![]).unwrap();
+        }
+
+        let heights = |blocks: Vec<Block>| blocks.iter().map(|b| b.height).collect::<Vec<_>>();
+        let backward = bc.iterator().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(heights(backward), vec![3, 2, 1, 0]);
+        let forward = bc.blocks_from_genesis().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(heights(forward), vec![0, 1, 2, 3]);
+
+        let range = bc.blocks_in_range(Some(1), Some(2)).unwrap();
+        assert_eq!(range.len(), 2);
+        assert_eq!(heights(range.rev().collect::<Result<Vec<_>>>().unwrap()), vec![2, 1]);
+        assert_eq!(bc.blocks_in_range(Some(3), None).unwrap().len(), 1);
+        assert_eq!(bc.blocks_in_range(Some(5), None).unwrap().len(), 0);
+        assert!(bc.blocks_in_range(Some(2), Some(1)).is_err());
+
+        // 回滚后高度索引指向新链，被回滚的区块不再能找到
+        let old_tip = bc.tip.clone();
+        assert_eq!(bc.find_block("3").unwrap().hash, old_tip);
+        bc.rollback("1").unwrap();
+        assert!(bc.find_block("3").is_err());
+        assert!(bc.find_block(&old_tip).is_err());
+        let new_tip = bc.mine_block(vec![]).unwrap().get_hash();
+        assert_eq!(bc.find_block("2").unwrap().hash, new_tip);
+        assert_eq!(bc.headers_in_range(Some(1), None).unwrap().len(), 2);
+    }
+    #[test]
+    fn test_read_only_alongside_writer() {
+        let path = std::env::temp_dir().join(format!("btc-read-only-test-{}", std::process::id()));
+        let datadir = DataDir::new(&path).unwrap();
+        let wallet = Wallet::new_wallet();
+        let _lock = datadir.lock().unwrap();
+        let mut bc = Blockchain::create_block_chain(wallet.get_address(), &datadir).unwrap();
+        bc.write_checkpoint().unwrap();
+
+        // 写入的进程持有锁并且数据库还在打开时，多个查询同时读取上一个检查点
+        bc.mine_block(vec![]).unwrap();
+        let first = Blockchain::open_read_only(&datadir).unwrap();
+        let second = Blockchain::open_read_only(&datadir).unwrap();
+        assert_eq!(first.get_best_height().unwrap(), 0);
+        assert_eq!(second.get_best_height().unwrap(), 0);
+
+        bc.mine_block(vec![]).unwrap();
+        bc.write_checkpoint().unwrap();
+        assert_eq!(Blockchain::open_read_only(&datadir).unwrap().get_best_height().unwrap(), 2);
+        assert_eq!(first.get_best_height().unwrap(), 0);
+        drop(bc);
+        std::fs::remove_dir_all(path).unwrap();
     }
 }
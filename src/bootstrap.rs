@@ -0,0 +1,207 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    block::Block,
+    blockchain::{db_exists, Blockchain},
+    datadir::DataDir,
+};
+
+// 导出文件的开头，后面是从创世块开始的区块，每个区块为 4 字节大端长度 + 序列化数据
+const MAGIC: &[u8; 4] = b"BTCB";
+const PROGRESS_INTERVAL: usize = 100;
+
+pub fn export_chain(bc: &Blockchain, path: &Path) -> Result<usize> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+
+    let blocks = bc.blocks_from_genesis()?;
+    let total = blocks.len();
+    for (count, block) in blocks.enumerate() {
+        let data = block?.serialize()?;
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(data.as_bytes())?;
+        if (count + 1) % PROGRESS_INTERVAL == 0 {
+            println!("Exported {} of {} blocks", count + 1, total);
+        }
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    pub imported: usize,
+    pub skipped: usize, // 已经在链上的区块
+}
+
+// 逐个验证并接入文件中的区块，每个区块单独提交，中断后重新执行会跳过已经导入的区块
+pub fn import_chain(datadir: &DataDir, path: &Path) -> Result<ImportStats> {
+    let mut reader = BlockReader::open(path)?;
+    let mut stats = ImportStats::default();
+
+    let mut bc = if db_exists(datadir) {
+        Blockchain::new_block_chain(datadir)?
+    } else {
+        let genesis = reader
+            .next_block()?
+            .ok_or(anyhow!("Bootstrap file contains no blocks"))?;
+        // 创世块和它的 UTXO 在同一个批次中写入，中断后可以直接重新导入
+        let bc = Blockchain::create_block_chain_from_genesis(genesis, datadir)?;
+        stats.imported += 1;
+        bc
+    };
+
+    while let Some(block) = reader.next_block()? {
+        // 只跳过当前链上的区块，回滚掉的区块还保存在存储中，需要重新接入
+        let active = bc.get_store().get_hash_at(block.get_height())?;
+        if active.as_deref() == Some(block.get_hash().as_str()) {
+            stats.skipped += 1;
+        } else {
+            bc.import_block(&block)?;
+            stats.imported += 1;
+        }
+        if (stats.imported + stats.skipped) % PROGRESS_INTERVAL == 0 {
+            println!(
+                "Processed {} blocks, height {}",
+                stats.imported + stats.skipped,
+                block.get_height()
+            );
+        }
+    }
+    Ok(stats)
+}
+
+pub struct BlockReader {
+    reader: BufReader<File>,
+}
+
+impl BlockReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow!("{} is not a bootstrap file", path.display()));
+        }
+        Ok(Self { reader })
+    }
+
+    // 读到文件结尾时返回 None，最后一个区块不完整时返回错误
+    pub fn next_block(&mut self) -> Result<Option<Block>> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+        self.reader
+            .read_exact(&mut data)
+            .map_err(|e| anyhow!("Bootstrap file is truncated: {e}"))?;
+        Ok(Some(Block::deserialize(std::str::from_utf8(&data)?)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Write, path::PathBuf, sync::Arc};
+
+    use crate::{
+        block::Block,
+        blockchain::Blockchain,
+        config::Config,
+        datadir::DataDir,
+        store::MemoryStore,
+        testutil::{new_chain, spend_to_self},
+        utxoset::UTXOSet,
+    };
+
+    use super::{export_chain, import_chain, MAGIC};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("btc-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let (wallet, _, mut bc, coinbase) = new_chain(Config::default());
+        let split = spend_to_self(&wallet, &coinbase, 0, vec![10, 40]);
+        bc.mine_block(vec![split.clone()]).unwrap();
+        let merged = spend_to_self(&wallet, &split, 1, vec![40]);
+        bc.mine_block(vec![merged]).unwrap();
+
+        let file = temp_path("export.dat");
+        assert_eq!(export_chain(&bc, &file).unwrap(), 3);
+        let target = DataDir::new(temp_path("export-dst")).unwrap();
+        let stats = import_chain(&target, &file).unwrap();
+        assert_eq!((stats.imported, stats.skipped), (3, 0));
+        {
+            let imported = Blockchain::new_block_chain(&target).unwrap();
+            assert_eq!(imported.tip, bc.tip);
+            UTXOSet::new(imported).check_consistency().unwrap();
+        }
+        // 重新导入跳过已有的区块
+        let stats = import_chain(&target, &file).unwrap();
+        assert_eq!((stats.imported, stats.skipped), (0, 3));
+        // 回滚之后重新导入被回滚的区块
+        Blockchain::new_block_chain(&target)
+            .unwrap()
+            .rollback("0")
+            .unwrap();
+        let stats = import_chain(&target, &file).unwrap();
+        assert_eq!((stats.imported, stats.skipped), (2, 1));
+        {
+            let imported = Blockchain::new_block_chain(&target).unwrap();
+            assert_eq!(imported.tip, bc.tip);
+            UTXOSet::new(imported).check_consistency().unwrap();
+        }
+
+        // 输出超过输入的区块不能导入
+        let minted = spend_to_self(&wallet, &coinbase, 0, vec![60]);
+        let genesis = bc.iterator().last().unwrap().unwrap();
+        let block = Block::new_block(genesis.get_hash(), 1, vec![minted]).unwrap();
+        let mut writer = fs::File::create(&file).unwrap();
+        writer.write_all(MAGIC).unwrap();
+        for block in [genesis, block] {
+            let data = block.serialize().unwrap();
+            writer
+                .write_all(&(data.len() as u32).to_be_bytes())
+                .unwrap();
+            writer.write_all(data.as_bytes()).unwrap();
+        }
+        drop(writer);
+        let minting = DataDir::new(temp_path("export-mint")).unwrap();
+        let err = import_chain(&minting, &file).unwrap_err().to_string();
+        assert!(err.contains("more than its inputs"), "{err}");
+
+        for dir in [&target, &minting] {
+            fs::remove_dir_all(dir.path()).unwrap();
+        }
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_main_network_snapshot() {
+        let file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("bootstrap/main.dat");
+        let datadir = DataDir::new(temp_path("main-net")).unwrap();
+        import_chain(&datadir, &file).unwrap();
+        let bc = Blockchain::new_block_chain(&datadir).unwrap();
+
+        // 内置的快照不需要 config.json 就能加载
+        let snapshot = UTXOSet::new(bc.clone()).dump(2).unwrap();
+        UTXOSet::new(bc.clone()).load(&snapshot, false).unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let fresh = Blockchain::create_from_snapshot(store, &snapshot, Config::default(), false);
+        assert_eq!(fresh.unwrap().tip, bc.tip);
+
+        drop(bc);
+        fs::remove_dir_all(datadir.path()).unwrap();
+    }
+}
@@ -1,8 +1,16 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
+use crate::address::{Address, AddressEncoding};
+
 #[derive(Parser)]
 #[command(name = "blockchain", version, about="a simple btc", long_about = None)]
 pub struct Cli {
+    /// Directory holding the block database, wallet and config. Logs go to debug.log in this
+    /// directory, warnings and errors are also printed to stderr
+    #[arg(long, global = true, env = "BTC_DATADIR", default_value = ".")]
+    pub datadir: PathBuf,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -16,35 +24,312 @@ pub enum Commands {
         #[arg(short, long)]
         data: String,
     },
-    /// Print block chain info
+    /// Print block chain info, from the tip down to the genesis block
     #[command(name = "printchain")]
-    PrintChain,
+    PrintChain {
+        /// Lowest block height to print
+        #[arg(long)]
+        from: Option<u64>,
+        /// Highest block height to print
+        #[arg(long)]
+        to: Option<u64>,
+    },
     /// Create block chain
     #[command(name = "createblockchain")]
     CreateBlockChain {
         /// 创世的地址
         #[arg(short, long)]
-        address: String,
+        address: Address,
     },
-    /// Get balance
+    /// Get the balance of an address, or of the wallet and its watch-only addresses
     #[command(name = "getbalance")]
     GetBalance {
         #[arg(short, long)]
-        address: String,
+        address: Option<Address>,
     },
+    /// Check the length, version and checksum of an address
+    #[command(name = "validateaddress")]
+    ValidateAddress { address: String },
     /// Send to other
     #[command(name = "send")]
     Send {
         #[arg(short, long)]
-        from: String,
+        from: Address,
         #[arg(short, long)]
-        to: String,
+        to: Address,
         #[arg(short, long)]
         amount: isize,
+        /// Fee paid out of the change
+        #[arg(long, default_value_t = 0)]
+        fee: isize,
+        /// Allow the transaction to be replaced by bumpfee while unconfirmed
+        #[arg(long)]
+        rbf: bool,
+        /// Put the transaction into the mempool instead of mining it right away
+        #[arg(long)]
+        no_mine: bool,
+        /// Note kept with the transaction in the wallet
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Replace an unconfirmed transaction with one paying a higher fee
+    #[command(name = "bumpfee")]
+    BumpFee {
+        txid: String,
+        /// New total fee, defaults to the old fee plus one
+        #[arg(long)]
+        fee: Option<isize>,
     },
-    /// Create walletssssss
+    /// Mine all transactions in the mempool into a new block
+    #[command(name = "mine")]
+    Mine,
+    /// Print the transactions in the mempool
+    #[command(name = "getmempool")]
+    GetMempool,
+    /// Create a new address derived from the wallet seed
     #[command(name = "createwallet")]
-    CreateWallet,
+    CreateWallet {
+        /// Text format of the new address
+        #[arg(long, value_enum, default_value_t)]
+        encoding: AddressEncoding,
+    },
+    /// Print the mnemonic that backs up the wallet seed
+    #[command(name = "getmnemonic")]
+    GetMnemonic,
+    /// Recreate the wallet seed from a mnemonic and find its used addresses on the chain
+    #[command(name = "restorewallet")]
+    RestoreWallet {
+        /// Words separated by spaces
+        #[arg(long)]
+        mnemonic: String,
+    },
+    /// Track the transactions and balance of an address without its private key
+    #[command(name = "importaddress")]
+    ImportAddress {
+        #[arg(short, long)]
+        address: Address,
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+    /// Track the address of a public key without its private key
+    #[command(name = "importpubkey")]
+    ImportPubkey {
+        /// Hex encoded SEC1 public key
+        #[arg(long)]
+        pubkey: String,
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+    /// Get a new address for receiving change
+    #[command(name = "getrawchangeaddress")]
+    GetRawChangeAddress {
+        #[arg(long, value_enum, default_value_t)]
+        encoding: AddressEncoding,
+    },
+    /// Encrypt the secret keys in the wallet with a passphrase
+    #[command(name = "encryptwallet")]
+    EncryptWallet {
+        #[arg(short, long)]
+        passphrase: String,
+    },
+    /// Unlock the encrypted wallet for a number of seconds
+    #[command(name = "walletpassphrase")]
+    WalletPassphrase {
+        #[arg(short, long)]
+        passphrase: String,
+        /// Seconds before the wallet is locked again
+        #[arg(short, long)]
+        timeout: u64,
+    },
+    /// Change the passphrase of the encrypted wallet
+    #[command(name = "walletpassphrasechange")]
+    WalletPassphraseChange {
+        #[arg(long)]
+        old: String,
+        #[arg(long)]
+        new: String,
+    },
+    /// Lock the encrypted wallet before the unlock timeout
+    #[command(name = "walletlock")]
+    WalletLock,
+    /// Background process started by walletpassphrase that keeps the key in memory
+    #[command(name = "walletagent", hide = true)]
+    WalletAgent {
+        #[arg(long)]
+        socket: PathBuf,
+        #[arg(long)]
+        timeout: u64,
+    },
     #[command(name = "reindex")]
     Reindex,
+    /// Disconnect blocks until the given block is the tip
+    #[command(name = "rollback")]
+    Rollback {
+        /// Height or hash of the new tip
+        #[arg(long)]
+        to: String,
+    },
+    /// Write all blocks, genesis first, to a bootstrap file
+    #[command(name = "exportchain")]
+    ExportChain { file: PathBuf },
+    /// Validate and connect the blocks of a bootstrap file, skipping blocks already in the chain
+    #[command(name = "importchain")]
+    ImportChain { file: PathBuf },
+    /// Write the UTXO set at a height and its hash to a file
+    #[command(name = "dumputxo")]
+    DumpUtxo {
+        file: PathBuf,
+        /// Defaults to the current tip
+        #[arg(long)]
+        height: Option<u64>,
+    },
+    /// Replace the UTXO set with a snapshot whose hash is pinned in the chain parameters.
+    /// With no block database, start the chain from the snapshot's headers
+    #[command(name = "loadutxo")]
+    LoadUtxo {
+        file: PathBuf,
+        /// Also accept the assume_utxo entries in config.json, overriding the built-in hashes
+        #[arg(long)]
+        trust_config: bool,
+    },
+    /// Check the block database for corruption and report the first inconsistency
+    #[command(name = "verifychain")]
+    VerifyChain {
+        /// 1: blocks readable, 2: proof of work and linkage, 3: signatures, 4: UTXO set
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=4))]
+        level: u8,
+    },
+    /// Upgrade the block database to the current schema version
+    #[command(name = "migrate")]
+    Migrate {
+        /// Only print the migration steps
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Compare the UTXO set with the outputs recomputed from the blocks
+    #[command(name = "checkutxo")]
+    CheckUtxo,
+    /// Print the confirmed transactions of an address, newest first (needs addrindex)
+    #[command(name = "history")]
+    History {
+        #[arg(short, long)]
+        address: Address,
+        /// Number of newest entries to skip
+        #[arg(long, default_value_t = 0)]
+        skip: usize,
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Print the transactions recorded by the wallet, unconfirmed and newest first
+    #[command(name = "listtransactions")]
+    ListTransactions {
+        #[arg(long, default_value_t = 0)]
+        skip: usize,
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Print the details of a wallet transaction
+    #[command(name = "gettransaction")]
+    GetTransaction { txid: String },
+    /// Set the label of an address, an empty label removes it
+    #[command(name = "setlabel")]
+    SetLabel {
+        #[arg(short, long)]
+        address: Address,
+        #[arg(short, long)]
+        label: String,
+    },
+    /// Lock coins to a hash time-locked contract, refundable to `from` after the timeout
+    #[command(name = "createhtlc")]
+    CreateHtlc {
+        #[arg(short, long)]
+        from: Address,
+        /// Address that can claim with the preimage
+        #[arg(short, long)]
+        to: Address,
+        #[arg(short, long)]
+        amount: isize,
+        /// Hex encoded sha256 of the preimage
+        #[arg(long)]
+        hashlock: String,
+        /// Number of blocks after which `from` can refund
+        #[arg(long)]
+        timeout: u64,
+    },
+    /// Claim a HTLC output by revealing the preimage
+    #[command(name = "claimhtlc")]
+    ClaimHtlc {
+        #[arg(long)]
+        txid: String,
+        #[arg(long)]
+        vout: isize,
+        /// Hex encoded preimage
+        #[arg(long)]
+        preimage: String,
+        /// Defaults to the receiver address of the HTLC
+        #[arg(short, long)]
+        to: Option<Address>,
+    },
+    /// Refund a HTLC output after its timeout
+    #[command(name = "refundhtlc")]
+    RefundHtlc {
+        #[arg(long)]
+        txid: String,
+        #[arg(long)]
+        vout: isize,
+        /// Defaults to the refund address of the HTLC
+        #[arg(short, long)]
+        to: Option<Address>,
+    },
+}
+
+impl Commands {
+    // 只读取数据的命令，读取检查点，可以和修改数据的命令同时运行
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Commands::PrintChain { .. }
+                | Commands::GetBalance { .. }
+                | Commands::ValidateAddress { .. }
+                | Commands::GetMempool
+                | Commands::ExportChain { .. }
+                | Commands::DumpUtxo { .. }
+                | Commands::VerifyChain { .. }
+                | Commands::CheckUtxo
+                | Commands::History { .. }
+                | Commands::ListTransactions { .. }
+                | Commands::GetTransaction { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use clap::{Arg, CommandFactory, FromArgMatches};
+
+    use super::Cli;
+
+    // 去掉 BTC_DATADIR，结果不受运行测试的环境影响；环境变量的情况见 tests/datadir.rs
+    #[test]
+    fn test_datadir_resolution() {
+        let datadir = |args: &[&str]| {
+            let args = ["blockchain"].iter().chain(args);
+            let command = Cli::command().mut_arg("datadir", |arg: Arg| arg.env(None));
+            Cli::from_arg_matches(&command.try_get_matches_from(args).unwrap())
+                .unwrap()
+                .datadir
+        };
+        assert_eq!(datadir(&["printchain"]), PathBuf::from("."));
+        assert_eq!(
+            datadir(&["--datadir", "/a", "printchain"]),
+            PathBuf::from("/a")
+        );
+        // 全局参数也可以放在子命令之后
+        assert_eq!(
+            datadir(&["printchain", "--datadir", "/a"]),
+            PathBuf::from("/a")
+        );
+    }
 }
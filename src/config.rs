@@ -0,0 +1,45 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{datadir::DataDir, params::AssumeUtxo};
+
+// 至少保留的区块数，保证最近的区块可以回滚
+pub const MIN_PRUNE_DEPTH: u64 = 6;
+
+// 数据目录下 config.json 中的节点配置，缺省的字段使用默认值
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // 维护 txid => 区块位置 的索引，修改后需要执行 reindex
+    pub txindex: bool,
+    // 维护 pubkey hash => 交易记录 的索引，修改后需要执行 reindex
+    pub addrindex: bool,
+    // 只保留最近 prune 个区块的数据，更早的区块只保留区块头
+    pub prune: Option<u64>,
+    // 可信的 UTXO 快照，只有 loadutxo --trust-config 时才使用，覆盖内置的值
+    pub assume_utxo: Vec<AssumeUtxo>,
+}
+
+impl Config {
+    pub fn load(datadir: &DataDir) -> Result<Self> {
+        let config: Self = match fs::read_to_string(datadir.config_path()) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // 打开链之前检查，避免区块写入之后才发现配置错误
+    pub fn validate(&self) -> Result<()> {
+        match self.prune {
+            Some(depth) if depth < MIN_PRUNE_DEPTH => Err(anyhow!(
+                "Invalid config.json: prune must be at least {MIN_PRUNE_DEPTH}, got {depth}"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+
+const SALT_LEN: usize = 16;
+pub const KEY_LEN: usize = 32;
+// 用来检查口令是否正确的明文
+const CHECK_DATA: &[u8] = b"simple-btc-wallet";
+
+// 加密后的数据，nonce 和密文都用 hex 保存
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Sealed {
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+// 从口令派生密钥需要的参数，密钥本身不保存
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MasterKey {
+    pub salt: String,
+    pub check: Sealed,
+}
+
+impl MasterKey {
+    // 生成新的盐，返回参数和派生出的密钥
+    pub fn new(passphrase: &str) -> Result<(Self, [u8; KEY_LEN])> {
+        if passphrase.is_empty() {
+            return Err(anyhow!("Passphrase can not be empty"));
+        }
+        let salt: [u8; SALT_LEN] = rand_bytes();
+        let key = derive_key(passphrase, &salt)?;
+        let master_key = Self {
+            salt: hex::encode(salt),
+            check: seal(&key, CHECK_DATA)?,
+        };
+        Ok((master_key, key))
+    }
+
+    // 口令错误时返回错误
+    pub fn unlock(&self, passphrase: &str) -> Result<[u8; KEY_LEN]> {
+        let key = derive_key(passphrase, &hex::decode(&self.salt)?)?;
+        match open(&key, &self.check) {
+            Ok(data) if data == CHECK_DATA => Ok(key),
+            _ => Err(anyhow!("The wallet passphrase entered was incorrect")),
+        }
+    }
+}
+
+// argon2 拉伸口令，增加暴力破解的成本
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; KEY_LEN]> {
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Derive wallet key err: {e}"))?;
+    Ok(key)
+}
+
+pub fn seal(key: &[u8; KEY_LEN], plaintext: &[u8]) -> Result<Sealed> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|e| anyhow!("Encrypt err: {e}"))?;
+    Ok(Sealed {
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+// 密钥错误或者数据被修改时认证失败
+pub fn open(key: &[u8; KEY_LEN], sealed: &Sealed) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = hex::decode(&sealed.nonce)?;
+    if nonce.len() != 12 {
+        return Err(anyhow!("Invalid nonce length {}", nonce.len()));
+    }
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            hex::decode(&sealed.ciphertext)?.as_slice(),
+        )
+        .map_err(|_| anyhow!("Decrypt failed, wrong key or corrupted data"))
+}
+
+fn rand_bytes<const N: usize>() -> [u8; N] {
+    use chacha20poly1305::aead::rand_core::RngCore;
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
@@ -0,0 +1,100 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use fs2::FileExt;
+
+const DB_FILE: &str = "btc_data";
+const WALLET_FILE: &str = "wallet.dat";
+const CONFIG_FILE: &str = "config.json";
+const LOG_FILE: &str = "debug.log";
+const LOCK_FILE: &str = ".lock";
+// 写入命令完成时导出的区块数据库，查询命令读取它，不和写入的进程争用数据库
+const CHECKPOINT_FILE: &str = "btc_data.checkpoint";
+
+// 节点的数据目录，区块数据库、钱包、配置和日志都放在这个目录下
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    path: PathBuf,
+}
+
+impl DataDir {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.path.join(DB_FILE)
+    }
+
+    pub fn checkpoint_path(&self) -> PathBuf {
+        self.path.join(CHECKPOINT_FILE)
+    }
+
+    pub fn wallet_path(&self) -> PathBuf {
+        self.path.join(WALLET_FILE)
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.path.join(CONFIG_FILE)
+    }
+
+    pub fn log_path(&self) -> PathBuf {
+        self.path.join(LOG_FILE)
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        self.path.join(LOCK_FILE)
+    }
+
+    // 修改区块数据库或钱包前获取，同一时间只有一个进程可以持有
+    pub fn lock(&self) -> Result<DataDirLock> {
+        let file = open_lock_file(&self.lock_path())?;
+        file.try_lock_exclusive().map_err(|_| {
+            anyhow!(
+                "Data directory {} is in use by another process",
+                self.path.display()
+            )
+        })?;
+        Ok(DataDirLock { file })
+    }
+}
+
+fn open_lock_file(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?)
+}
+
+// drop 时关闭文件，锁随之释放
+#[derive(Debug)]
+pub struct DataDirLock {
+    file: File,
+}
+
+#[cfg(test)]
+mod test {
+    use super::DataDir;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let path = std::env::temp_dir().join(format!("btc-lock-test-{}", std::process::id()));
+        let datadir = DataDir::new(&path).unwrap();
+        let lock = datadir.lock().unwrap();
+        let err = datadir.lock().unwrap_err().to_string();
+        assert!(err.contains("in use by another process"), "{err}");
+        drop(lock);
+        assert!(datadir.lock().is_ok());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Result};
+use ecdsa::elliptic_curve::{sec1::ToEncodedPoint, PrimeField, SecretKey};
+use hmac::{Hmac, Mac};
+use p256::{NistP256, Scalar};
+use sha2::Sha512;
+
+// 按 SLIP-0010 在 P-256 曲线上从种子派生密钥，路径的格式和 BIP-32 相同
+const CURVE_SEED_KEY: &[u8] = b"Nist256p1 seed";
+pub const HARDENED: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone)]
+pub struct ExtendedKey {
+    secret_key: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn from_seed(seed: &[u8]) -> Result<Self> {
+        let mut data = seed.to_vec();
+        // 得到的私钥不合法时用上一次的结果重新计算
+        loop {
+            let (il, ir) = hmac_sha512(CURVE_SEED_KEY, &data)?;
+            if parse_scalar(&il).is_some_and(|k| k != Scalar::ZERO) {
+                return Ok(Self {
+                    secret_key: il,
+                    chain_code: ir,
+                });
+            }
+            data = [il, ir].concat();
+        }
+    }
+
+    // 路径形如 m/0'/1'/5，带 ' 或 h 的是硬化派生
+    pub fn derive_path(&self, path: &str) -> Result<Self> {
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(anyhow!("Derivation path {path} must start with m"));
+        }
+        let mut key = self.clone();
+        for part in parts {
+            let (number, hardened) = match part.strip_suffix(['\'', 'h']) {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            let index: u32 = number
+                .parse()
+                .ok()
+                .filter(|i| *i < HARDENED)
+                .ok_or(anyhow!("Invalid index {part} in derivation path {path}"))?;
+            key = key.derive_child(if hardened { index + HARDENED } else { index })?;
+        }
+        Ok(key)
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self> {
+        let k = parse_scalar(&self.secret_key).ok_or(anyhow!("Invalid extended key"))?;
+        let mut data = if index >= HARDENED {
+            [&[0u8][..], &self.secret_key].concat()
+        } else {
+            self.compressed_public_key()?
+        };
+        data.extend_from_slice(&index.to_be_bytes());
+        loop {
+            let (il, ir) = hmac_sha512(&self.chain_code, &data)?;
+            if let Some(child) = parse_scalar(&il).map(|il| il + k) {
+                if child != Scalar::ZERO {
+                    return Ok(Self {
+                        secret_key: child.to_repr().into(),
+                        chain_code: ir,
+                    });
+                }
+            }
+            data = [&[1u8][..], &ir, &index.to_be_bytes()].concat();
+        }
+    }
+
+    pub fn secret_key(&self) -> Vec<u8> {
+        self.secret_key.to_vec()
+    }
+
+    // 和 P256Ecdsa::new_key_pair 相同的非压缩编码
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        let secret_key = SecretKey::<NistP256>::from_slice(&self.secret_key)?;
+        Ok(secret_key.public_key().to_sec1_bytes().to_vec())
+    }
+
+    fn compressed_public_key(&self) -> Result<Vec<u8>> {
+        let secret_key = SecretKey::<NistP256>::from_slice(&self.secret_key)?;
+        Ok(secret_key
+            .public_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec())
+    }
+}
+
+// 大于等于曲线的阶时返回 None
+fn parse_scalar(bytes: &[u8; 32]) -> Option<Scalar> {
+    Scalar::from_repr((*bytes).into()).into()
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key)?;
+    mac.update(data);
+    let result = mac.finalize().into_bytes();
+    let mut il = [0u8; 32];
+    let mut ir = [0u8; 32];
+    il.copy_from_slice(&result[..32]);
+    ir.copy_from_slice(&result[32..]);
+    Ok((il, ir))
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExtendedKey;
+
+    // SLIP-0010 nist256p1 的测试向量 1
+    #[test]
+    fn test_slip10_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::from_seed(&seed).unwrap();
+        assert_eq!(
+            hex::encode(master.secret_key()),
+            "612091aaa12e22dd2abef664f8a01a82cae99ad7441b7ef8110424915c268bc2"
+        );
+        assert_eq!(
+            hex::encode(master.compressed_public_key().unwrap()),
+            "0266874dc6ade47b3ecd096745ca09bcd29638dd52c2c12117b11ed3e458cfa9e8"
+        );
+        let child = master.derive_path("m/0'").unwrap();
+        assert_eq!(
+            hex::encode(child.secret_key()),
+            "6939694369114c67917a182c59ddb8cafc3004e63ca5d3b84403ba8613debc0c"
+        );
+        let child = master.derive_path("m/0'/1").unwrap();
+        let expected = master
+            .derive_child(1 << 31)
+            .unwrap()
+            .derive_child(1)
+            .unwrap();
+        assert_eq!(child.secret_key(), expected.secret_key());
+        assert!(master.derive_path("0'/1").is_err());
+        assert!(master.derive_path("m/x").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::Block,
+    store::{Batch, ChainStore, ADDRINDEX, TXINDEX},
+    transaction::Transaction,
+    wallet::hash_pubkey,
+};
+
+// 记录索引已经同步到的区块，txid 是64位hex，不会和它冲突
+const TXINDEX_BEST: &str = "best";
+
+// 交易所在的区块以及在区块中的位置
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub position: usize,
+}
+
+pub fn get_tx_location(store: &dyn ChainStore, txid: &str) -> Result<Option<TxLocation>> {
+    match store.get(TXINDEX, txid.as_bytes())? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+// 索引同步到的区块hash，没有建立过索引时返回 None
+pub fn txindex_best(store: &dyn ChainStore) -> Result<Option<String>> {
+    match store.get(TXINDEX, TXINDEX_BEST.as_bytes())? {
+        Some(data) => Ok(Some(String::from_utf8(data)?)),
+        None => Ok(None),
+    }
+}
+
+// 把区块中的交易加入索引，并把索引的进度推进到这个区块
+pub fn index_block_txs(batch: &mut Batch, block: &Block) -> Result<()> {
+    for (position, tx) in block.transactions.iter().enumerate() {
+        let location = TxLocation {
+            block_hash: block.get_hash(),
+            position,
+        };
+        batch.insert(TXINDEX, &tx.id, serde_json::to_string(&location)?);
+    }
+    set_txindex_best(batch, block.get_hash().as_str());
+    Ok(())
+}
+
+// 区块被回滚时删除它的交易，索引的进度退回到前一个区块
+pub fn unindex_block_txs(batch: &mut Batch, block: &Block) {
+    for tx in block.transactions.iter() {
+        batch.remove(TXINDEX, &tx.id);
+    }
+    set_txindex_best(batch, block.get_prehash().as_str());
+}
+
+pub fn set_txindex_best(batch: &mut Batch, hash: &str) {
+    batch.insert(TXINDEX, TXINDEX_BEST, hash);
+}
+
+// 地址索引的同步进度，pubkey hash 是40位hex，不会和它冲突
+const ADDRINDEX_BEST: &str = "best";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+// 地址相关的一笔交易，同一笔交易既花费又找零给同一个地址时会有两条记录
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AddrTxEntry {
+    pub txid: String,
+    pub height: u64,
+    pub direction: Direction,
+    pub amount: isize,
+}
+
+pub fn get_address_history(store: &dyn ChainStore, pubkey_hash: &str) -> Result<Vec<AddrTxEntry>> {
+    match store.get(ADDRINDEX, pubkey_hash.as_bytes())? {
+        Some(data) => Ok(serde_json::from_slice(&data)?),
+        None => Ok(vec![]),
+    }
+}
+
+pub fn addrindex_best(store: &dyn ChainStore) -> Result<Option<String>> {
+    match store.get(ADDRINDEX, ADDRINDEX_BEST.as_bytes())? {
+        Some(data) => Ok(Some(String::from_utf8(data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn set_addrindex_best(batch: &mut Batch, hash: &str) {
+    batch.insert(ADDRINDEX, ADDRINDEX_BEST, hash);
+}
+
+// 计算区块中每个地址的收支记录，prev_txs 需要包含区块中所有输入引用的交易
+pub fn address_entries(
+    block: &Block,
+    prev_txs: &HashMap<String, Transaction>,
+) -> Result<HashMap<String, Vec<AddrTxEntry>>> {
+    let mut entries: HashMap<String, Vec<AddrTxEntry>> = HashMap::new();
+    for tx in block.transactions.iter() {
+        let mut sent: HashMap<String, isize> = HashMap::new();
+        if !tx.is_coinbase() {
+            for vin in tx.vin.iter() {
+                let prev_out = prev_txs
+                    .get(&vin.txid)
+                    .and_then(|prev| prev.vout.get(vin.vout as usize))
+                    .ok_or(anyhow!("Input {}:{} not found", vin.txid, vin.vout))?;
+                let (pubkey_hash, _) = prev_out.spending_key(vin);
+                *sent.entry(pubkey_hash).or_default() += prev_out.value;
+            }
+        }
+
+        let mut received: HashMap<String, isize> = HashMap::new();
+        for out in tx.vout.iter() {
+            let (pubkey_hash, _) = out.receiver();
+            *received.entry(pubkey_hash.to_string()).or_default() += out.value;
+        }
+
+        for (direction, amounts) in [(Direction::Sent, sent), (Direction::Received, received)] {
+            for (pubkey_hash, amount) in amounts {
+                entries.entry(pubkey_hash).or_default().push(AddrTxEntry {
+                    txid: tx.id.clone(),
+                    height: block.get_height(),
+                    direction,
+                    amount,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// 区块上链时把地址记录追加到索引中
+pub fn connect_block_addresses(
+    store: &dyn ChainStore,
+    batch: &mut Batch,
+    block: &Block,
+    prev_txs: &HashMap<String, Transaction>,
+) -> Result<()> {
+    for (pubkey_hash, entries) in address_entries(block, prev_txs)? {
+        let mut history = get_address_history(store, &pubkey_hash)?;
+        history.extend(entries);
+        batch.insert(ADDRINDEX, &pubkey_hash, serde_json::to_string(&history)?);
+    }
+    set_addrindex_best(batch, block.get_hash().as_str());
+    Ok(())
+}
+
+// 区块被回滚时从索引中删除它们的交易记录，blocks 从最新的区块开始连续排列
+pub fn disconnect_block_addresses(
+    store: &dyn ChainStore,
+    batch: &mut Batch,
+    blocks: &[Block],
+) -> Result<()> {
+    let Some(oldest) = blocks.last() else {
+        return Ok(());
+    };
+    let txs = blocks.iter().flat_map(|block| block.transactions.iter());
+    let txids: HashSet<&str> = txs.clone().map(|tx| tx.id.as_str()).collect();
+    let mut pubkey_hashes = HashSet::new();
+    for tx in txs {
+        if !tx.is_coinbase() {
+            for vin in tx.vin.iter() {
+                pubkey_hashes.insert(hex::encode(hash_pubkey(&vin.pubkey)));
+            }
+        }
+        for out in tx.vout.iter() {
+            match &out.htlc {
+                Some(htlc) => pubkey_hashes.insert(htlc.receiver_pubkey_hash.clone()),
+                None => pubkey_hashes.insert(out.pubkey_hash.clone()),
+            };
+        }
+    }
+
+    for pubkey_hash in pubkey_hashes {
+        let history: Vec<AddrTxEntry> = get_address_history(store, &pubkey_hash)?
+            .into_iter()
+            .filter(|e| !txids.contains(e.txid.as_str()))
+            .collect();
+        if history.is_empty() {
+            batch.remove(ADDRINDEX, &pubkey_hash);
+        } else {
+            batch.insert(ADDRINDEX, &pubkey_hash, serde_json::to_string(&history)?);
+        }
+    }
+    set_addrindex_best(batch, oldest.get_prehash().as_str());
+    Ok(())
+}
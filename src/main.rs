@@ -1,97 +1,549 @@
 #![allow(unused_variables, dead_code, unused_imports)]
 
+use std::{collections::HashSet, fs::OpenOptions, io, sync::Mutex};
+
 use anyhow::Result;
 use block::Block;
 use blockchain::Blockchain;
 use clap::Parser;
+use tracing::{error, Level};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
 use cli::Cli;
-use wallet::Wallets;
+use wallet::{TxCategory, Wallets};
 
 use crate::{
-    proof_of_work::ProofOfWork, transaction::Transaction, utxoset::UTXOSet,
-    wallet::pubkey_hash_from_base58,
+    address::Address,
+    datadir::DataDir,
+    mempool::Mempool,
+    proof_of_work::ProofOfWork, transaction::{Transaction, TxOutput}, utxoset::{UTXOSet, UtxoSnapshot},
+    scheme::SchemeTag,
 };
 
+mod address;
+mod agent;
+mod bech32;
 mod block;
+mod bootstrap;
 mod blockchain;
 mod cli;
+mod config;
+mod crypter;
+mod datadir;
 mod error;
+mod hd;
+mod index;
+mod mempool;
+mod params;
 mod proof_of_work;
+mod schema;
+mod scheme;
+mod sigcache;
+mod store;
+#[cfg(test)]
+mod testutil;
 mod transaction;
 mod utxoset;
+mod verify;
 mod wallet;
 
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
     let cli = Cli::parse();
+    let datadir = DataDir::new(cli.datadir)?;
+
+    // 所有日志写入数据目录中的 debug.log，warn 和 error 同时输出到 stderr
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(datadir.log_path())?;
+    tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(Mutex::new(log_file).and(io::stderr.with_max_level(Level::WARN)))
+        .init();
 
     // let mut bc = Blockchain::new_block_chain("0xxxxxxx".into()).unwrap();
     // bc.add_block("Send 1 btc to Zhangsan".into())?;
     // bc.add_block("Send 2 more btc to ZhangSan".into())?;
 
-    match cli.command {
+    // agent 一直运行到解锁超时，不能持有数据目录的锁
+    if let cli::Commands::WalletAgent { socket, timeout } = &cli.command {
+        return agent::serve(socket, *timeout);
+    }
+
+    // 查询命令读取检查点，不需要锁；修改数据的命令独占数据目录，完成后导出新的检查点，
+    // 命令失败时也可能已经提交了一部分修改
+    if cli.command.is_read_only() {
+        return run(cli.command, datadir);
+    }
+    let _lock = datadir.lock()?;
+    let result = run(cli.command, datadir.clone());
+    if blockchain::db_exists(&datadir) {
+        let written =
+            Blockchain::open_for_migration(&datadir).and_then(|bc| bc.write_checkpoint());
+        if let Err(e) = written {
+            error!("Write checkpoint failed, queries see the data before this command: {e}");
+        }
+    }
+    result
+}
+
+fn run(command: cli::Commands, datadir: DataDir) -> Result<()> {
+    match command {
         cli::Commands::Addblock { data } => {
             println!("Success!")
         }
         cli::Commands::CreateBlockChain { address } => {
-            let bc = Blockchain::create_block_chain(address)?;
-            let utxoset = UTXOSet::new(bc);
-            utxoset.reindex()?;
+            let bc = Blockchain::create_block_chain(address.to_string(), &datadir)?;
+            sync_wallet(&bc, &datadir);
             println!("Done");
         }
 
-        cli::Commands::CreateWallet => {
-            let mut wallets = Wallets::new_wallets()?;
-            let address = wallets.create_wallet();
+        cli::Commands::CreateWallet { encoding } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            let address = wallets.create_wallet(encoding)?;
             wallets.save_to_file()?;
             println!("Your new address: {address}")
         }
+        cli::Commands::GetMnemonic => {
+            let wallets = Wallets::new_wallets(&datadir)?;
+            println!("{}", wallets.get_mnemonic()?);
+        }
+        cli::Commands::RestoreWallet { mnemonic } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            wallets.restore(&mnemonic)?;
+            if blockchain::db_exists(&datadir) {
+                let bc = Blockchain::new_block_chain(&datadir)?;
+                let found = wallets.rescan(&bc.used_pubkey_hashes()?)?;
+                println!("Wallet restored, found {found} used addresses");
+            } else {
+                println!("Wallet restored, create or import a chain to find its used addresses");
+            }
+            wallets.save_to_file()?;
+        }
+        cli::Commands::ImportAddress { address, label } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            wallets.import_address(&address)?;
+            import_watch_only(&mut wallets, &address, label, &datadir)?;
+        }
+        cli::Commands::ImportPubkey { pubkey, label } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            let address = wallets.import_pubkey(&hex::decode(pubkey)?, SchemeTag::P256Ecdsa)?;
+            import_watch_only(&mut wallets, &address, label, &datadir)?;
+        }
+        cli::Commands::GetRawChangeAddress { encoding } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            let address = wallets.get_change_address(encoding)?;
+            wallets.save_to_file()?;
+            println!("{address}");
+        }
+        cli::Commands::EncryptWallet { passphrase } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            wallets.encrypt(&passphrase)?;
+            println!("Wallet encrypted, unlock it with walletpassphrase to send");
+        }
+        cli::Commands::WalletPassphrase {
+            passphrase,
+            timeout,
+        } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            wallets.unlock(&passphrase)?;
+            wallets.start_agent(timeout)?;
+            println!("Wallet unlocked for {timeout} seconds");
+        }
+        cli::Commands::WalletPassphraseChange { old, new } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            wallets.change_passphrase(&old, &new)?;
+            println!("Wallet passphrase changed");
+        }
+        cli::Commands::WalletAgent { .. } => unreachable!("handled before locking the datadir"),
+        cli::Commands::WalletLock => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            if !wallets.is_encrypted() {
+                return Err(anyhow::anyhow!("Wallet is not encrypted"));
+            }
+            wallets.lock()?;
+            println!("Wallet locked");
+        }
         cli::Commands::GetBalance { address } => {
-            let bc = Blockchain::new_block_chain()?;
-
-            let pubek_hash = pubkey_hash_from_base58(address.as_str())?;
+            let bc = Blockchain::open_read_only(&datadir)?;
 
             let utxoset = UTXOSet::new(bc);
+            let balance_of = |pubkey_hashes: HashSet<String>| -> Result<isize> {
+                let mut balance = 0;
+                for pubkey_hash in pubkey_hashes {
+                    for out in utxoset.find_utxo(&pubkey_hash)? {
+                        balance += out.value;
+                    }
+                }
+                Ok(balance)
+            };
 
-            let utxos = utxoset.find_utxo(pubek_hash.as_str())?;
-            let mut balance = 0;
-            for out in utxos {
-                balance += out.value;
+            match address {
+                Some(address) => {
+                    let balance = balance_of(HashSet::from([address.pubkey_hash()]))?;
+                    println!("Balance of {}:{}", address, balance);
+                }
+                // 只观察的地址单独统计，它们的币不能从这个钱包花费
+                None => {
+                    let wallets = Wallets::new_wallets(&datadir)?;
+                    println!("Balance: {}", balance_of(wallets.spendable_pubkey_hashes())?);
+                    println!(
+                        "Watch-only balance: {}",
+                        balance_of(wallets.watch_only_pubkey_hashes())?
+                    );
+                }
             }
-
-            println!("Balance of {}:{}", address, balance);
         }
-        cli::Commands::Send { from, to, amount } => {
-            let mut bc = Blockchain::new_block_chain()?;
-            let tx = Transaction::new_utxo_transaction(from, to, amount, &bc)?;
+        cli::Commands::ValidateAddress { address } => match address.parse::<Address>() {
+            Ok(parsed) => {
+                println!("{parsed} is valid");
+                println!("scheme: {:?}", parsed.scheme());
+                println!("pubkey hash: {}", parsed.pubkey_hash());
+            }
+            Err(e) => println!("invalid: {e}"),
+        },
+        cli::Commands::Send {
+            from,
+            to,
+            amount,
+            fee,
+            rbf,
+            no_mine,
+            note,
+        } => {
+            let mut bc = Blockchain::new_block_chain(&datadir)?;
+            let tx = Transaction::new_utxo_transaction(&from, &to, amount, fee, rbf, &bc)?;
+            let txid = tx.id.clone();
+            if no_mine {
+                Mempool::new(bc.clone()).add(tx)?;
+            } else {
+                let block = bc.mine_block(vec![tx])?;
+                Mempool::new(bc.clone()).remove_for_block(&block)?;
+            }
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            match wallets.sync(&bc) {
+                Ok(()) => {
+                    if let Some(note) = note {
+                        wallets.set_note(&txid, &note)?;
+                    }
+                    wallets.save_to_file()?;
+                }
+                Err(e) => warn_wallet_not_synced(e),
+            }
+            if no_mine {
+                println!("Transaction {txid} added to mempool");
+            } else {
+                println!("Send Success!");
+            }
+        }
+        cli::Commands::BumpFee { txid, fee } => {
+            let bc = Blockchain::new_block_chain(&datadir)?;
+            let tx = Transaction::new_bumpfee_transaction(txid.as_str(), fee, &bc)?;
+            let new_txid = tx.id.clone();
+            Mempool::new(bc.clone()).add(tx)?;
+            sync_wallet(&bc, &datadir);
+            println!("Transaction {txid} replaced by {new_txid}");
+        }
+        cli::Commands::Mine => {
+            let mut bc = Blockchain::new_block_chain(&datadir)?;
+            let mempool = Mempool::new(bc.clone());
+            let txs = mempool.transactions()?;
+            if txs.is_empty() {
+                println!("Mempool is empty");
+                return Ok(());
+            }
+            let block = bc.mine_block(txs)?;
+            mempool.remove_for_block(&block)?;
+            sync_wallet(&bc, &datadir);
+            println!("Mine Success!");
+        }
+        cli::Commands::GetMempool => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let mempool = Mempool::new(bc);
+            for tx in mempool.transactions()? {
+                let fee = mempool.fee(&tx)?;
+                println!("{} fee: {} replaceable: {}", tx.id, fee, tx.signals_rbf());
+            }
+        }
+        cli::Commands::CreateHtlc {
+            from,
+            to,
+            amount,
+            hashlock,
+            timeout,
+        } => {
+            let mut bc = Blockchain::new_block_chain(&datadir)?;
+            let timeout = bc.get_best_height()? + 1 + timeout;
+            let tx = Transaction::new_htlc_transaction(&from, &to, amount, hashlock, timeout, &bc)?;
+            let txid = tx.id.clone();
             let block = bc.mine_block(vec![tx])?;
-            let utxoset = UTXOSet::new(bc);
-            utxoset.update(block)?;
-            println!("Send Success!");
+            Mempool::new(bc.clone()).remove_for_block(&block)?;
+            sync_wallet(&bc, &datadir);
+            println!("HTLC output: {txid}:0, refundable from height {timeout}");
+        }
+        cli::Commands::ClaimHtlc {
+            txid,
+            vout,
+            preimage,
+            to,
+        } => {
+            let mut bc = Blockchain::new_block_chain(&datadir)?;
+            let preimage = hex::decode(preimage)?;
+            let tx = Transaction::new_htlc_spend_transaction(txid, vout, to, Some(preimage), &bc)?;
+            let block = bc.mine_block(vec![tx])?;
+            Mempool::new(bc.clone()).remove_for_block(&block)?;
+            sync_wallet(&bc, &datadir);
+            println!("Claim Success!");
+        }
+        cli::Commands::RefundHtlc { txid, vout, to } => {
+            let mut bc = Blockchain::new_block_chain(&datadir)?;
+            let tx = Transaction::new_htlc_spend_transaction(txid, vout, to, None, &bc)?;
+            let block = bc.mine_block(vec![tx])?;
+            Mempool::new(bc.clone()).remove_for_block(&block)?;
+            sync_wallet(&bc, &datadir);
+            println!("Refund Success!");
         }
         cli::Commands::Reindex => {
-            let bc = Blockchain::new_block_chain()?;
+            let bc = Blockchain::new_block_chain(&datadir)?;
+            bc.reindex_txindex()?;
+            bc.reindex_addrindex()?;
             let utxoset = UTXOSet::new(bc);
             utxoset.reindex()?;
             println!("Reindex ok!");
         }
-        cli::Commands::PrintChain => {
-            let bc = Blockchain::new_block_chain()?;
-            let mut iterator = bc.iterator();
-            loop {
-                let block = iterator.next()?;
-                println!("Prev. hash: {}", block.get_prehash());
-                println!("Transaction: {:?}", block.transactions);
-                println!("Hash: {}", block.get_hash());
-                let pow = ProofOfWork::new_proof_of_work(block.clone());
-                println!("POW: {}", pow.validate());
-                println!("");
-                if block.get_prehash().is_empty() {
-                    break;
+        cli::Commands::Rollback { to } => {
+            let mut bc = Blockchain::new_block_chain(&datadir)?;
+            let blocks = bc.rollback(&to)?;
+            // 被回滚的交易放回交易池，从旧的区块开始，无法加入的直接丢弃
+            let mempool = Mempool::new(bc.clone());
+            let mut restored = 0;
+            for block in blocks.iter().rev() {
+                for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                    if mempool.add(tx.clone()).is_ok() {
+                        restored += 1;
+                    }
                 }
             }
+            sync_wallet(&bc, &datadir);
+            println!(
+                "Rolled back {} blocks, new tip: {}, {} transactions returned to mempool",
+                blocks.len(),
+                bc.tip,
+                restored
+            );
+        }
+        cli::Commands::ExportChain { file } => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let count = bootstrap::export_chain(&bc, &file)?;
+            println!("Exported {count} blocks to {}", file.display());
+        }
+        cli::Commands::ImportChain { file } => {
+            let stats = bootstrap::import_chain(&datadir, &file)?;
+            println!(
+                "Imported {} blocks, skipped {} blocks already in the chain",
+                stats.imported, stats.skipped
+            );
+        }
+        cli::Commands::DumpUtxo { file, height } => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let height = match height {
+                Some(height) => height,
+                None => bc.get_best_height()?,
+            };
+            let snapshot = UTXOSet::new(bc).dump(height)?;
+            snapshot.save_to_file(&file)?;
+            println!(
+                "Dumped {} UTXOs at height {} block {}",
+                snapshot.coins.len(),
+                snapshot.height,
+                snapshot.block_hash
+            );
+            println!("UTXO hash: {}", snapshot.utxo_hash);
+        }
+        cli::Commands::LoadUtxo { file, trust_config } => {
+            let snapshot = UtxoSnapshot::load_from_file(&file)?;
+            if blockchain::db_exists(&datadir) {
+                let bc = Blockchain::new_block_chain(&datadir)?;
+                UTXOSet::new(bc).load(&snapshot, trust_config)?;
+            } else {
+                Blockchain::create_block_chain_from_snapshot(&snapshot, &datadir, trust_config)?;
+                println!("Started the chain from the snapshot, add later blocks with importchain");
+            }
+            println!("Loaded {} UTXOs from height {}", snapshot.coins.len(), snapshot.height);
+        }
+        cli::Commands::VerifyChain { level } => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let report = verify::verify_chain(&bc, level)?;
+            println!("No inconsistencies found in {} blocks", report.blocks);
+        }
+        cli::Commands::Migrate { dry_run } => {
+            let bc = Blockchain::open_for_migration(&datadir)?;
+            let steps = schema::migrate(&bc, dry_run)?;
+            if steps.is_empty() {
+                println!("Schema is up to date (version {})", schema::SCHEMA_VERSION);
+            } else if dry_run {
+                println!("{} migration steps pending", steps.len());
+            } else {
+                println!("Migrated to schema version {}", schema::SCHEMA_VERSION);
+            }
+        }
+        cli::Commands::CheckUtxo => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            UTXOSet::new(bc).check_consistency()?;
+            println!("UTXO set is consistent");
+        }
+        cli::Commands::History {
+            address,
+            skip,
+            count,
+        } => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let history = bc.address_history(&address.pubkey_hash())?;
+            for entry in history.iter().rev().skip(skip).take(count) {
+                println!(
+                    "{} height: {} {:?} {}",
+                    entry.txid, entry.height, entry.direction, entry.amount
+                );
+            }
+        }
+        cli::Commands::ListTransactions { skip, count } => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            // 查询命令只更新内存中的记录，不写钱包文件
+            wallets.sync(&bc)?;
+            let best_height = bc.get_best_height()?;
+            for wtx in wallets.list_transactions().into_iter().skip(skip).take(count) {
+                let category = wallets.category(&wtx.tx);
+                // 支出显示收款方的地址，收入显示钱包的地址
+                let out = wtx
+                    .tx
+                    .vout
+                    .iter()
+                    .find(|out| wallets.is_mine(out) != (category == TxCategory::Send))
+                    .or(wtx.tx.vout.first());
+                let address = match out {
+                    Some(out) => format_output_address(&wallets, out)?,
+                    None => "-".to_string(),
+                };
+                let watch_only = if wallets.involves_watch_only(&wtx.tx) {
+                    " (watch-only)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{} {} {:?} {} confirmations: {}{watch_only}",
+                    wtx.tx.id,
+                    address,
+                    category,
+                    wallets.received(&wtx.tx) - wallets.sent(&wtx.tx),
+                    wtx.confirmations(best_height)
+                );
+            }
+        }
+        cli::Commands::GetTransaction { txid } => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            wallets.sync(&bc)?;
+            let wtx = wallets.get_transaction(&txid)?;
+            let category = wallets.category(&wtx.tx);
+            println!("txid: {}", wtx.tx.id);
+            println!("category: {:?}", category);
+            println!("amount: {}", wallets.received(&wtx.tx) - wallets.sent(&wtx.tx));
+            if let Some(fee) = wallets.fee(&wtx.tx).filter(|_| category == TxCategory::Send) {
+                println!("fee: {fee}");
+            }
+            println!("confirmations: {}", wtx.confirmations(bc.get_best_height()?));
+            match (wtx.height, &wtx.block_hash) {
+                (Some(height), Some(hash)) => println!("block: {hash} height: {height}"),
+                _ => println!("block: in mempool"),
+            }
+            if let Some(note) = &wtx.note {
+                println!("note: {note}");
+            }
+            for (i, out) in wtx.tx.vout.iter().enumerate() {
+                let mine = if wallets.is_watch_only(out) {
+                    " (watch-only)"
+                } else if wallets.is_mine(out) {
+                    " (mine)"
+                } else {
+                    ""
+                };
+                println!(
+                    "output {i}: {} {}{mine}",
+                    format_output_address(&wallets, out)?,
+                    out.value
+                );
+            }
+        }
+        cli::Commands::SetLabel { address, label } => {
+            let mut wallets = Wallets::new_wallets(&datadir)?;
+            wallets.set_label(&address, &label);
+            wallets.save_to_file()?;
+            println!("Done");
+        }
+        cli::Commands::PrintChain { from, to } => {
+            let bc = Blockchain::open_read_only(&datadir)?;
+            let store = bc.get_store();
+            for header in bc.headers_in_range(from, to)?.into_iter().rev() {
+                println!("Prev. hash: {}", header.prev_block_hash);
+                match store.get_block(&header.hash)? {
+                    Some(block) => println!("Transaction: {:?}", block.transactions),
+                    None => println!("Transaction: pruned"),
+                }
+                println!("Hash: {}", header.hash);
+                println!("POW: {}", proof_of_work::validate_header(&header));
+                println!();
+            }
+        }
+    }
+    Ok(())
+}
+
+// 链或内存池改变后把新的交易记录到钱包中，数据已经提交，
+// 同步失败只给出警告，下次同步时会补上
+fn sync_wallet(bc: &Blockchain, datadir: &DataDir) {
+    let synced = Wallets::new_wallets(datadir).and_then(|mut wallets| {
+        wallets.sync(bc)?;
+        Ok(wallets.save_to_file()?)
+    });
+    if let Err(e) = synced {
+        warn_wallet_not_synced(e);
+    }
+}
+
+fn warn_wallet_not_synced(e: anyhow::Error) {
+    error!("Wallet sync failed: {e}");
+    println!("Warning: the wallet was not updated: {e}");
+}
+
+// 地址后面带上标签
+fn format_output_address(wallets: &Wallets, out: &TxOutput) -> Result<String> {
+    let address = wallets.output_address(out)?;
+    Ok(match wallets.get_label(&address) {
+        Some(label) => format!("{address} [{label}]"),
+        None => address.to_string(),
+    })
+}
+
+// 导入后重新扫描链上的交易，没有链时等创建或导入链之后再扫描
+fn import_watch_only(
+    wallets: &mut Wallets,
+    address: &Address,
+    label: Option<String>,
+    datadir: &DataDir,
+) -> Result<()> {
+    if let Some(label) = label {
+        wallets.set_label(address, &label);
+    }
+    if blockchain::db_exists(datadir) {
+        let bc = Blockchain::new_block_chain(datadir)?;
+        wallets.sync(&bc)?;
+        if let Some(height) = bc.get_store().get_prune_height()? {
+            println!(
+                "Blocks up to height {height} are pruned, only their unspent outputs were rescanned"
+            );
         }
     }
+    wallets.save_to_file()?;
+    println!("Watching {address}");
     Ok(())
 }
@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    block::Block,
+    blockchain::{check_values, Blockchain},
+    sigcache,
+    store::{Batch, MEMPOOL},
+    transaction::Transaction,
+};
+
+// 还没有被打包进区块的交易，key 为 txid
+pub struct Mempool {
+    bc: Blockchain,
+}
+
+impl Mempool {
+    pub fn new(bc: Blockchain) -> Self {
+        Self { bc }
+    }
+
+    pub fn get(&self, txid: &str) -> Result<Option<Transaction>> {
+        match self.bc.get_store().get(MEMPOOL, txid.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn transactions(&self) -> Result<Vec<Transaction>> {
+        let mut txs = vec![];
+        for (_, value) in self.bc.get_store().scan(MEMPOOL)? {
+            txs.push(serde_json::from_slice(&value)?);
+        }
+        Ok(txs)
+    }
+
+    // 交易池中被花费的输出 (txid, vout) => 花费它的交易id
+    pub fn spent_outpoints(&self) -> Result<HashMap<(String, isize), String>> {
+        let mut spent = HashMap::new();
+        for tx in self.transactions()? {
+            for vin in tx.vin.iter() {
+                spent.insert((vin.txid.clone(), vin.vout), tx.id.clone());
+            }
+        }
+        Ok(spent)
+    }
+
+    pub fn fee(&self, tx: &Transaction) -> Result<isize> {
+        let prev_txs = self.bc.prev_transactions(std::slice::from_ref(tx))?;
+        tx.fee(&prev_txs)
+    }
+
+    // 加入一笔交易，和交易池中的交易花费了相同的输出时，只有被替换的交易都允许替换，
+    // 并且新交易的手续费严格高于被替换交易的手续费总和时才接受
+    pub fn add(&self, tx: Transaction) -> Result<()> {
+        if tx.is_coinbase() {
+            return Err(anyhow!("Coinbase transaction can not be added to mempool"));
+        }
+        if self.get(&tx.id)?.is_some() {
+            return Err(anyhow!("Transaction {} already in mempool", tx.id));
+        }
+        // 输入必须是链上未花费的输出
+        let store = self.bc.get_store();
+        for vin in tx.vin.iter() {
+            if store.get_utxo(&vin.txid, vin.vout)?.is_none() {
+                return Err(anyhow!(
+                    "Input {}:{} is spent or does not exist",
+                    vin.txid,
+                    vin.vout
+                ));
+            }
+        }
+        let prev_txs = self.bc.prev_transactions(std::slice::from_ref(&tx))?;
+        let checks = match tx.signature_checks(&prev_txs, self.bc.get_best_height()? + 1)? {
+            Some(checks) if checks.iter().all(|c| self.bc.get_sigcache().verify(c)) => checks,
+            _ => return Err(anyhow!("Verity tx failed")),
+        };
+        check_values(&tx, &prev_txs)?;
+        let fee = tx.fee(&prev_txs)?;
+
+        let spent = self.spent_outpoints()?;
+        let conflicts: HashSet<String> = tx
+            .vin
+            .iter()
+            .filter_map(|vin| spent.get(&(vin.txid.clone(), vin.vout)).cloned())
+            .collect();
+
+        let mut conflict_fee = 0;
+        for txid in conflicts.iter() {
+            let conflict = self
+                .get(txid)?
+                .ok_or(anyhow!("Get mempool tx {txid}, return None"))?;
+            if !conflict.signals_rbf() {
+                return Err(anyhow!(
+                    "Transaction conflicts with non-replaceable transaction {txid}"
+                ));
+            }
+            conflict_fee += self.fee(&conflict)?;
+        }
+        if !conflicts.is_empty() && fee <= conflict_fee {
+            return Err(anyhow!(
+                "Replacement fee {fee} must be higher than the replaced fee {conflict_fee}"
+            ));
+        }
+
+        let mut batch = Batch::default();
+        for txid in conflicts.iter() {
+            batch.remove(MEMPOOL, txid);
+            sigcache::forget(&mut batch, txid);
+        }
+        batch.insert(MEMPOOL, &tx.id, serde_json::to_string(&tx)?);
+        sigcache::persist(&mut batch, &tx.id, &checks)?;
+        self.bc.get_store().apply(batch)
+    }
+
+    // 区块上链后，移除区块中的交易以及和它们冲突的交易
+    pub fn remove_for_block(&self, block: &Block) -> Result<()> {
+        let mut spent_by_block = HashSet::new();
+        let mut included = HashSet::new();
+        for tx in block.transactions.iter() {
+            included.insert(tx.id.clone());
+            for vin in tx.vin.iter() {
+                spent_by_block.insert((vin.txid.clone(), vin.vout));
+            }
+        }
+
+        let mut batch = Batch::default();
+        for tx in self.transactions()? {
+            let conflicted = tx
+                .vin
+                .iter()
+                .any(|vin| spent_by_block.contains(&(vin.txid.clone(), vin.vout)));
+            if included.contains(&tx.id) || conflicted {
+                batch.remove(MEMPOOL, &tx.id);
+                sigcache::forget(&mut batch, &tx.id);
+            }
+        }
+        self.bc.get_store().apply(batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::Config,
+        testutil::{new_chain, sign, spend_to_self},
+        transaction::{Transaction, MAX_RBF_SEQUENCE},
+    };
+
+    use super::Mempool;
+
+    #[test]
+    fn test_replace_by_fee() {
+        let (wallet, _, mut bc, coinbase) = new_chain(Config::default());
+        let split = spend_to_self(&wallet, &coinbase, 0, vec![20, 30]);
+        bc.mine_block(vec![split.clone()]).unwrap();
+        let mempool = Mempool::new(bc.clone());
+        let replaceable = |vout: isize, values: Vec<isize>| -> Transaction {
+            let mut tx = spend_to_self(&wallet, &split, vout, values);
+            tx.vin[0].sequence = MAX_RBF_SEQUENCE;
+            sign(&wallet, &split, tx)
+        };
+
+        // 输入已经被花费、不存在，或者输出超过输入都不能加入
+        let err = mempool.add(spend_to_self(&wallet, &coinbase, 0, vec![50]));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("spent or does not exist"));
+        let mut missing = spend_to_self(&wallet, &split, 0, vec![1]);
+        missing.vin[0].vout = 2;
+        let err = mempool.add(missing);
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("spent or does not exist"));
+        let err = mempool.add(spend_to_self(&wallet, &split, 0, vec![21]));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("more than its inputs"));
+
+        // 替换需要严格更高的手续费，被替换的交易从交易池中移除
+        let first = replaceable(0, vec![19]);
+        mempool.add(first.clone()).unwrap();
+        let err = mempool
+            .add(replaceable(0, vec![10, 9]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Replacement fee"), "{err}");
+        let err = mempool
+            .add(spend_to_self(&wallet, &split, 0, vec![19]))
+            .unwrap_err();
+        assert!(err.to_string().contains("Replacement fee"));
+        let second = replaceable(0, vec![17]);
+        mempool.add(second.clone()).unwrap();
+        assert!(mempool.get(&first.id).unwrap().is_none());
+        assert_eq!(mempool.fee(&second).unwrap(), 3);
+
+        // 没有标记可替换的交易不能被替换
+        let fixed = spend_to_self(&wallet, &split, 1, vec![29]);
+        mempool.add(fixed.clone()).unwrap();
+        let err = mempool
+            .add(replaceable(1, vec![10]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("non-replaceable"), "{err}");
+        assert_eq!(mempool.transactions().unwrap().len(), 2);
+    }
+}
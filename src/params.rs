@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+// 可信的 UTXO 快照：某个高度的区块以及这个高度的 UTXO 集合的hash
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AssumeUtxo {
+    pub height: u64,
+    pub block_hash: String,
+    pub utxo_hash: String,
+}
+
+// Bech32 地址的前缀，区分不同网络的地址
+pub const ADDRESS_HRP: &str = "sbtc";
+
+// 内置的网络，按创世块区分。创世块由执行 createblockchain 的节点生成，
+// 其他节点用 importchain 导入同一个 bootstrap 文件加入这个网络
+struct Network {
+    genesis_hash: &'static str,
+    // (高度, 区块 hash, UTXO 集合 hash)
+    assume_utxo: &'static [(u64, &'static str, &'static str)],
+}
+
+const NETWORKS: &[Network] = &[
+    // bootstrap/main.dat
+    Network {
+        genesis_hash: "0014d34a8713bd5ddcbed387b3402256905eec87865284b88bae8e78239c06fc",
+        assume_utxo: &[(
+            2,
+            "003e007f1c0b2f26267259a454bb12d11225e065df638fe824163a005dd46607",
+            "96b31ee507809af3487756c6c257f4450f60a9d4fb8aae511c2d4d41061c771c",
+        )],
+    },
+];
+
+// 链参数，默认只包含内置的快照
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub assume_utxo: Vec<AssumeUtxo>,
+}
+
+impl ChainParams {
+    // 创世块为 genesis_hash 的网络的参数。config.json 可以被任何能写数据目录的人修改，
+    // 只有 trust_config 为 true 时才使用其中的 assume_utxo，并且覆盖同一高度的内置值
+    pub fn new(genesis_hash: &str, config: &Config, trust_config: bool) -> Self {
+        let mut assume_utxo = vec![];
+        if trust_config {
+            assume_utxo.extend(config.assume_utxo.iter().cloned());
+        }
+        let builtin = NETWORKS
+            .iter()
+            .filter(|network| network.genesis_hash == genesis_hash)
+            .flat_map(|network| network.assume_utxo.iter());
+        for (height, block_hash, utxo_hash) in builtin {
+            assume_utxo.push(AssumeUtxo {
+                height: *height,
+                block_hash: block_hash.to_string(),
+                utxo_hash: utxo_hash.to_string(),
+            });
+        }
+        Self { assume_utxo }
+    }
+
+    pub fn assume_utxo_at(&self, height: u64) -> Option<&AssumeUtxo> {
+        self.assume_utxo.iter().find(|a| a.height == height)
+    }
+}
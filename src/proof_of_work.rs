@@ -3,7 +3,7 @@ use std::{cmp::Ordering, ops::Shl};
 
 use num_bigint::{BigInt, ToBigInt};
 
-use crate::block::Block;
+use crate::block::{Block, BlockHeader};
 
 const TARGET_BITS: usize = 10;
 
@@ -14,25 +14,22 @@ pub struct ProofOfWork {
 
 impl ProofOfWork {
     pub fn new_proof_of_work(block: Block) -> Self {
-        let mut target: BigInt = 1.to_bigint().unwrap();
-        target = target.shl(256 - TARGET_BITS);
-
         Self {
-            block: block,
-            target,
+            block,
+            target: target(),
         }
     }
 
-    pub fn prepare_data(&self, nonce: u128) -> String {
-        let data = format!(
-            "{}:{}:{}:{}:{}",
-            self.block.get_prehash(),
-            self.block.hash_transactions(),
+    pub fn prepare_data(&self, nonce: u128) -> Result<String> {
+        let data = header_data(
+            &self.block.get_prehash(),
+            self.block.get_height(),
+            &self.block.hash_transactions(),
+            &self.block.hash_witnesses()?,
             self.block.get_timestamp(),
-            TARGET_BITS,
-            nonce
+            nonce,
         );
-        data
+        Ok(data)
     }
 
     pub fn run(&self) -> Result<(u128, String)> {
@@ -40,7 +37,7 @@ impl ProofOfWork {
         let mut hash: String = "".into();
         // println!("Mining the block containing {}", self.block.);
         while nonce < u128::MAX {
-            hash = self.prepare_data(nonce);
+            hash = self.prepare_data(nonce)?;
             hash = sha256::digest(hash);
 
             let big_hash = BigInt::parse_bytes(hash.as_bytes(), 16).unwrap();
@@ -54,19 +51,53 @@ impl ProofOfWork {
                 }
             }
         }
-        println!("");
+        println!();
         Ok((nonce, hash))
     }
 
-    pub fn validate(&self) -> bool {
-        let data = self.prepare_data(self.block.get_nonce());
-        let hash = sha256::digest(data);
+    // 按区块中的 nonce 重新计算的hash
+    pub fn hash(&self) -> Result<String> {
+        Ok(sha256::digest(self.prepare_data(self.block.get_nonce())?))
+    }
+
+    pub fn validate(&self) -> Result<bool> {
+        let hash = self.hash()?;
         let hash_big = BigInt::parse_bytes(hash.as_bytes(), 16).unwrap();
 
-        if Ordering::Greater != hash_big.cmp(&self.target) {
-            true
-        } else {
-            false
-        }
+        Ok(Ordering::Greater != hash_big.cmp(&self.target))
     }
 }
+
+fn target() -> BigInt {
+    let target: BigInt = 1.to_bigint().unwrap();
+    target.shl(256 - TARGET_BITS)
+}
+
+fn header_data(
+    prehash: &str,
+    height: u64,
+    transactions_hash: &str,
+    witnesses_hash: &str,
+    timestamp: u128,
+    nonce: u128,
+) -> String {
+    format!(
+        "{}:{}:{}:{}:{}:{}:{}",
+        prehash, height, transactions_hash, witnesses_hash, timestamp, TARGET_BITS, nonce
+    )
+}
+
+// 只有区块头时验证hash和工作量证明
+pub fn validate_header(header: &BlockHeader) -> bool {
+    let data = header_data(
+        &header.prev_block_hash,
+        header.height,
+        &header.transactions_hash,
+        &header.witnesses_hash,
+        header.timestamp,
+        header.nonce,
+    );
+    let hash = sha256::digest(data);
+    let hash_big = BigInt::parse_bytes(hash.as_bytes(), 16).unwrap();
+    hash == header.hash && Ordering::Greater != hash_big.cmp(&target())
+}
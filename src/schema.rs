@@ -0,0 +1,211 @@
+use std::str::from_utf8;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    blockchain::Blockchain,
+    proof_of_work::validate_header,
+    store::{Batch, ChainStore, BLOCKS, HEIGHTS, META, SCHEMA_VERSION_KEY},
+    utxoset::UTXOSet,
+};
+
+// 当前的存储格式版本，修改 tree 或编码时增加版本并添加对应的迁移；
+// 修改 txid 或区块hash的计算方式不能通过迁移完成，由 check_proof_of_work 拒绝旧链
+pub const SCHEMA_VERSION: u32 = 3;
+
+// 把存储从 from 版本升级到 from + 1，中断后可以重新执行
+struct Migration {
+    from: u32,
+    description: &'static str,
+    run: fn(&Blockchain) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "assign heights to blocks stored without one",
+        run: assign_heights,
+    },
+    Migration {
+        from: 1,
+        description: "key the UTXO set by outpoint",
+        run: rebuild_chainstate,
+    },
+    Migration {
+        from: 2,
+        description: "index block hashes by height",
+        run: index_heights,
+    },
+];
+
+// 没有版本记录的存储是最初的格式，版本为 0
+pub fn get_version(store: &dyn ChainStore) -> Result<u32> {
+    match store.get(META, SCHEMA_VERSION_KEY.as_bytes())? {
+        Some(data) => Ok(from_utf8(&data)?.parse()?),
+        None => Ok(0),
+    }
+}
+
+pub fn set_version(batch: &mut Batch, version: u32) {
+    batch.insert(META, SCHEMA_VERSION_KEY, version.to_string());
+}
+
+pub fn check_version(store: &dyn ChainStore) -> Result<()> {
+    let version = get_version(store)?;
+    if version > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "Data directory uses schema version {version}, newer than the supported version {SCHEMA_VERSION}"
+        ));
+    }
+    if version < SCHEMA_VERSION {
+        return Err(anyhow!(
+            "Data directory uses schema version {version}, run migrate to upgrade to version {SCHEMA_VERSION}"
+        ));
+    }
+    Ok(())
+}
+
+// 依次执行需要的迁移，每一步完成后记录版本；dry_run 时只返回需要执行的步骤
+pub fn migrate(bc: &Blockchain, dry_run: bool) -> Result<Vec<&'static str>> {
+    let store = bc.get_store();
+    let version = get_version(store.as_ref())?;
+    if version > SCHEMA_VERSION {
+        return check_version(store.as_ref()).map(|_| vec![]);
+    }
+
+    if version < SCHEMA_VERSION {
+        check_proof_of_work(bc)?;
+    }
+
+    let mut steps = vec![];
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        println!(
+            "Schema {} -> {}: {}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        );
+        if !dry_run {
+            (migration.run)(bc)?;
+            let mut batch = Batch::default();
+            set_version(&mut batch, migration.from + 1);
+            store.apply(batch)?;
+        }
+        steps.push(migration.description);
+    }
+    Ok(steps)
+}
+
+// txid 改为不包含签名和公钥、区块hash改为同时承诺见证数据之前创建的链，
+// 区块hash和交易之间的引用在新规则下都不成立，只能重新创建
+fn check_proof_of_work(bc: &Blockchain) -> Result<()> {
+    let genesis = bc
+        .headers()
+        .last()
+        .ok_or(anyhow!("Chain has no genesis block"))??;
+    if !validate_header(&genesis) {
+        return Err(anyhow!(
+            "Genesis block {} fails the current proof of work, the chain was created with older txid and block hash rules and can not be migrated, create a new chain",
+            genesis.hash
+        ));
+    }
+    Ok(())
+}
+
+// 早期的区块没有保存高度，反序列化后都是 0
+fn assign_heights(bc: &Blockchain) -> Result<()> {
+    let store = bc.get_store();
+    let hashes = bc
+        .headers()
+        .map(|header| header.map(|header| header.hash))
+        .collect::<Result<Vec<String>>>()?;
+
+    let mut batch = Batch::default();
+    for (height, hash) in hashes.iter().rev().enumerate() {
+        // 被裁剪的区块只有区块头，裁剪功能出现时区块已经有高度
+        if let Some(mut block) = store.get_block(hash)? {
+            if block.height != height as u64 {
+                block.height = height as u64;
+                batch.insert(BLOCKS, hash, block.serialize()?);
+            }
+        }
+    }
+    store.apply(batch)
+}
+
+// 重建时按高度遍历区块，需要先有高度索引
+fn rebuild_chainstate(bc: &Blockchain) -> Result<()> {
+    index_heights(bc)?;
+    UTXOSet::new(bc.clone()).migrate()?;
+    Ok(())
+}
+
+fn index_heights(bc: &Blockchain) -> Result<()> {
+    let mut batch = Batch::default();
+    batch.clear(HEIGHTS);
+    for header in bc.headers() {
+        let header = header?;
+        batch.set_height(header.height, &header.hash);
+    }
+    bc.get_store().apply(batch)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::Config,
+        store::{Batch, ChainStore, CHAINSTATE, HEIGHTS, META, SCHEMA_VERSION_KEY},
+        testutil::new_chain,
+        utxoset::UTXOSet,
+    };
+
+    use super::{check_version, get_version, migrate, set_version, SCHEMA_VERSION};
+
+    #[test]
+    fn test_migrate_legacy_store() {
+        let (_, store, bc, _) = new_chain(Config::default());
+        assert_eq!(get_version(store.as_ref()).unwrap(), SCHEMA_VERSION);
+        assert!(check_version(store.as_ref()).is_ok());
+
+        // 模拟没有版本记录、按交易保存 UTXO、没有高度索引的旧数据目录
+        let mut batch = Batch::default();
+        batch.remove(META, SCHEMA_VERSION_KEY);
+        batch.insert(CHAINSTATE, "legacy", "[]");
+        batch.clear(HEIGHTS);
+        store.apply(batch).unwrap();
+        let err = check_version(store.as_ref()).unwrap_err().to_string();
+        assert!(err.contains("run migrate"), "{err}");
+
+        assert_eq!(migrate(&bc, true).unwrap().len(), 3);
+        assert_eq!(get_version(store.as_ref()).unwrap(), 0);
+        assert!(bc.find_block("0").is_err());
+
+        assert_eq!(migrate(&bc, false).unwrap().len(), 3);
+        assert!(check_version(store.as_ref()).is_ok());
+        assert_eq!(bc.find_block("0").unwrap().hash, bc.tip);
+        UTXOSet::new(bc.clone()).check_consistency().unwrap();
+        assert!(migrate(&bc, false).unwrap().is_empty());
+
+        let mut batch = Batch::default();
+        set_version(&mut batch, SCHEMA_VERSION + 1);
+        store.apply(batch).unwrap();
+        let err = check_version(store.as_ref()).unwrap_err().to_string();
+        assert!(err.contains("newer"), "{err}");
+    }
+    #[test]
+    fn test_migrate_rejects_old_block_hashes() {
+        let (_, store, bc, _) = new_chain(Config::default());
+
+        // 旧规则下计算的区块hash和新规则下的工作量证明不一致
+        let mut genesis = store.get_block(&bc.tip).unwrap().unwrap();
+        genesis.timestamp += 1;
+        let mut batch = Batch::default();
+        batch.put_block(&genesis).unwrap();
+        batch.remove(META, SCHEMA_VERSION_KEY);
+        store.apply(batch).unwrap();
+
+        let err = migrate(&bc, true).unwrap_err().to_string();
+        assert!(err.contains("can not be migrated"), "{err}");
+        assert_eq!(get_version(store.as_ref()).unwrap(), 0);
+    }
+}
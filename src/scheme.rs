@@ -0,0 +1,147 @@
+use anyhow::Result;
+use ecdsa::{
+    elliptic_curve::{PublicKey, SecretKey},
+    signature::{rand_core::OsRng, Signer, Verifier},
+    Signature, SigningKey, VerifyingKey,
+};
+use p256::NistP256;
+use serde::{Deserialize, Serialize};
+
+// 签名算法的标识，和公钥/输出一起保存，验证时根据它选择对应的算法
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SchemeTag {
+    #[default]
+    P256Ecdsa,
+    #[cfg(test)]
+    Test,
+}
+
+impl SchemeTag {
+    // 地址中的版本字节，用来从地址中区分公钥使用的签名算法
+    pub fn address_version(&self) -> u8 {
+        match self {
+            SchemeTag::P256Ecdsa => 0x00,
+            #[cfg(test)]
+            SchemeTag::Test => 0x7f,
+        }
+    }
+
+    pub fn from_address_version(version: u8) -> Option<Self> {
+        match version {
+            0x00 => Some(SchemeTag::P256Ecdsa),
+            #[cfg(test)]
+            0x7f => Some(SchemeTag::Test),
+            _ => None,
+        }
+    }
+
+    pub fn scheme(&self) -> &'static dyn SignatureScheme {
+        match self {
+            SchemeTag::P256Ecdsa => &P256Ecdsa,
+            #[cfg(test)]
+            SchemeTag::Test => &test_scheme::TestScheme,
+        }
+    }
+}
+
+pub trait SignatureScheme: Send + Sync {
+    fn tag(&self) -> SchemeTag;
+
+    // 返回 (私钥, 公钥)
+    fn new_key_pair(&self) -> (Vec<u8>, Vec<u8>);
+
+    fn sign(&self, secret_key: &[u8], msg: &[u8]) -> Result<Vec<u8>>;
+
+    fn verify(&self, pubkey: &[u8], msg: &[u8], signature: &[u8]) -> Result<()>;
+
+    // 校验公钥并返回规范的编码
+    fn encode_pubkey(&self, pubkey: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct P256Ecdsa;
+
+impl SignatureScheme for P256Ecdsa {
+    fn tag(&self) -> SchemeTag {
+        SchemeTag::P256Ecdsa
+    }
+
+    fn new_key_pair(&self) -> (Vec<u8>, Vec<u8>) {
+        let signing_key: SigningKey<NistP256> = SigningKey::random(&mut OsRng);
+
+        let secret_key: SecretKey<NistP256> = signing_key.into();
+        let pubkey = secret_key.public_key();
+        (
+            secret_key.to_bytes().to_vec(),
+            pubkey.to_sec1_bytes().to_vec(),
+        )
+    }
+
+    fn sign(&self, secret_key: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+        let signing_key: SigningKey<NistP256> = SigningKey::from_slice(secret_key)?;
+        let signature: Signature<NistP256> = signing_key.try_sign(msg)?;
+        Ok(signature.to_vec())
+    }
+
+    fn verify(&self, pubkey: &[u8], msg: &[u8], signature: &[u8]) -> Result<()> {
+        let verfiying_key: VerifyingKey<NistP256> = VerifyingKey::from_sec1_bytes(pubkey)?;
+        let signature: Signature<NistP256> = Signature::from_slice(signature)?;
+        verfiying_key.verify(msg, &signature)?;
+        Ok(())
+    }
+
+    fn encode_pubkey(&self, pubkey: &[u8]) -> Result<Vec<u8>> {
+        let pubkey: PublicKey<NistP256> = PublicKey::from_sec1_bytes(pubkey)?;
+        Ok(pubkey.to_sec1_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+pub mod test_scheme {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use anyhow::{anyhow, Result};
+
+    use super::{SchemeTag, SignatureScheme};
+
+    static NEXT_KEY: AtomicU64 = AtomicU64::new(1);
+
+    // 只用于测试的确定性算法：公钥 = sha256(私钥)，签名 = sha256(公钥 + 消息)
+    pub struct TestScheme;
+
+    impl SignatureScheme for TestScheme {
+        fn tag(&self) -> SchemeTag {
+            SchemeTag::Test
+        }
+
+        fn new_key_pair(&self) -> (Vec<u8>, Vec<u8>) {
+            let secret_key = NEXT_KEY
+                .fetch_add(1, Ordering::SeqCst)
+                .to_be_bytes()
+                .to_vec();
+            let pubkey = hex::decode(sha256::digest(secret_key.as_slice())).unwrap();
+            (secret_key, pubkey)
+        }
+
+        fn sign(&self, secret_key: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+            let mut pubkey = hex::decode(sha256::digest(secret_key))?;
+            pubkey.extend(msg);
+            Ok(hex::decode(sha256::digest(pubkey))?)
+        }
+
+        fn verify(&self, pubkey: &[u8], msg: &[u8], signature: &[u8]) -> Result<()> {
+            let mut data = pubkey.to_vec();
+            data.extend(msg);
+            if hex::decode(sha256::digest(data))? != signature {
+                return Err(anyhow!("Test signature mismatch"));
+            }
+            Ok(())
+        }
+
+        fn encode_pubkey(&self, pubkey: &[u8]) -> Result<Vec<u8>> {
+            if pubkey.len() != 32 {
+                return Err(anyhow!("Test pubkey must be 32 bytes"));
+            }
+            Ok(pubkey.to_vec())
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::{
+    store::{Batch, ChainStore, SIGCACHE},
+    transaction::SigCheck,
+};
+
+// 缓存最多保存的条目数，超过后整体清空
+const MAX_ENTRIES: usize = 100_000;
+
+// 已经验证通过的签名缓存，key 为 sha256(scheme + sighash + pubkey + signature)
+// 交易在进入区块之前验证过一次后，打包区块时不需要再做签名验证，
+// 交易池中交易的签名同时保存在存储中，mine 等其他进程验证区块前通过 load 读入。
+// 每个 Blockchain 持有自己的缓存，打开不同存储的链不会互相命中
+#[derive(Debug, Default)]
+pub struct SigCache {
+    entries: Mutex<HashSet<String>>,
+}
+
+// 同样的公钥和签名在不同的签名算法下验证结果不同，key 需要包含算法
+fn cache_key(check: &SigCheck) -> String {
+    let mut data = vec![check.scheme.address_version()];
+    data.extend(check.sighash.as_bytes());
+    data.extend(&check.pubkey);
+    data.extend(check.signature.as_bytes());
+    sha256::digest(data)
+}
+
+impl SigCache {
+    // 缓存中没有时验证签名，验证通过后加入缓存
+    pub fn verify(&self, check: &SigCheck) -> bool {
+        if self.contains(check) {
+            return true;
+        }
+        let valid = check.verify();
+        if valid {
+            self.insert(check);
+        }
+        valid
+    }
+
+    pub fn contains(&self, check: &SigCheck) -> bool {
+        let key = cache_key(check);
+        match self.entries.lock() {
+            Ok(set) => set.contains(&key),
+            Err(_) => false,
+        }
+    }
+
+    pub fn insert(&self, check: &SigCheck) {
+        let key = cache_key(check);
+        if let Ok(mut set) = self.entries.lock() {
+            if set.len() >= MAX_ENTRIES {
+                set.clear();
+            }
+            set.insert(key);
+        }
+    }
+
+    pub fn load(&self, store: &dyn ChainStore) -> Result<()> {
+        let mut keys = vec![];
+        for (_, value) in store.scan(SIGCACHE)? {
+            keys.extend(serde_json::from_slice::<Vec<String>>(&value)?);
+        }
+        if let Ok(mut set) = self.entries.lock() {
+            if set.len() + keys.len() >= MAX_ENTRIES {
+                set.clear();
+            }
+            set.extend(keys);
+        }
+        Ok(())
+    }
+}
+
+// 和交易一起写入交易池，交易离开交易池时用 forget 删除
+pub fn persist(batch: &mut Batch, txid: &str, checks: &[SigCheck]) -> Result<()> {
+    let keys: Vec<String> = checks.iter().map(cache_key).collect();
+    batch.insert(SIGCACHE, txid, serde_json::to_string(&keys)?);
+    Ok(())
+}
+
+pub fn forget(batch: &mut Batch, txid: &str) {
+    batch.remove(SIGCACHE, txid);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        scheme::SchemeTag,
+        store::{Batch, ChainStore, MemoryStore},
+        transaction::SigCheck,
+    };
+
+    use super::{forget, persist, SigCache};
+
+    #[test]
+    fn test_sig_cache() {
+        let check = |sighash: &str| SigCheck {
+            scheme: SchemeTag::P256Ecdsa,
+            sighash: sighash.into(),
+            pubkey: vec![1, 2, 3],
+            signature: "sig-cache-test".into(),
+        };
+        let (a, b) = (check("aa"), check("bb"));
+        let cache = SigCache::default();
+        assert!(!cache.contains(&a));
+        cache.insert(&a);
+        assert!(cache.contains(&a));
+        assert!(!cache.contains(&SigCheck {
+            signature: "other signature".into(),
+            ..a.clone()
+        }));
+        // 其他签名算法下的同样的数据不命中
+        assert!(!cache.contains(&SigCheck {
+            scheme: SchemeTag::Test,
+            ..a.clone()
+        }));
+        // 缓存属于各自的链
+        assert!(!SigCache::default().contains(&a));
+
+        // 其他进程写入交易池的签名，加载之后命中
+        let store = MemoryStore::new();
+        let mut batch = Batch::default();
+        persist(&mut batch, "tx", std::slice::from_ref(&b)).unwrap();
+        store.apply(batch).unwrap();
+        assert!(!cache.contains(&b));
+        cache.load(&store).unwrap();
+        assert!(cache.contains(&b));
+
+        let mut batch = Batch::default();
+        forget(&mut batch, "tx");
+        store.apply(batch).unwrap();
+        assert!(store.scan(super::SIGCACHE).unwrap().is_empty());
+    }
+}
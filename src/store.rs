@@ -0,0 +1,529 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    str::from_utf8,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+
+use crate::{
+    block::{Block, BlockHeader},
+    utxoset::Coin,
+};
+
+// 区块数据，key 为区块hash；LAST 保存最新区块的hash
+pub const BLOCKS: &str = "blocks";
+pub const LAST: &str = "last";
+// 未花费输出集合，key 为 txid:vout
+pub const CHAINSTATE: &str = "chainstate";
+pub const MEMPOOL: &str = "mempool";
+// 交易池中交易已经验证过的签名，key 为 txid，value 为签名缓存的 key 列表
+pub const SIGCACHE: &str = "sigcache";
+// 可选的交易索引，key 为 txid
+pub const TXINDEX: &str = "txindex";
+// 可选的地址索引，key 为 pubkey hash
+pub const ADDRINDEX: &str = "addrindex";
+// 区块花费掉的输出，用于回滚，key 为区块hash
+pub const UNDO: &str = "undo";
+// 区块数据被裁剪后保留的区块头，key 为区块hash；PRUNE_HEIGHT 保存被裁剪的最高区块的高度
+pub const HEADERS: &str = "headers";
+pub const PRUNE_HEIGHT: &str = "pruneheight";
+// 当前链上每个高度的区块hash，key 为高度，回滚时删除
+pub const HEIGHTS: &str = "heights";
+// 存储的元数据，SCHEMA_VERSION_KEY 保存存储格式的版本
+pub const META: &str = "meta";
+pub const SCHEMA_VERSION_KEY: &str = "version";
+const OPEN_RETRIES: usize = 50;
+
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Insert {
+        tree: &'static str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        tree: &'static str,
+        key: Vec<u8>,
+    },
+    // 清空整个 tree 中原有的数据，之后的写入不受影响
+    Clear {
+        tree: &'static str,
+    },
+}
+
+// 一组需要原子写入的修改
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    ops: Vec<BatchOp>,
+    // 写入时 tip 必须还是这个区块，否则整个 batch 都不写入
+    expected_tip: Option<String>,
+}
+
+impl Batch {
+    pub fn insert(&mut self, tree: &'static str, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.ops.push(BatchOp::Insert {
+            tree,
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+    }
+
+    pub fn remove(&mut self, tree: &'static str, key: impl AsRef<[u8]>) {
+        self.ops.push(BatchOp::Remove {
+            tree,
+            key: key.as_ref().to_vec(),
+        });
+    }
+
+    pub fn clear(&mut self, tree: &'static str) {
+        self.ops.push(BatchOp::Clear { tree });
+    }
+
+    pub fn put_block(&mut self, block: &Block) -> Result<()> {
+        self.insert(BLOCKS, block.get_hash(), block.serialize()?);
+        Ok(())
+    }
+
+    pub fn set_tip(&mut self, hash: &str) {
+        self.insert(BLOCKS, LAST, hash);
+    }
+
+    pub fn set_height(&mut self, height: u64, hash: &str) {
+        self.insert(HEIGHTS, height.to_string(), hash);
+    }
+
+    pub fn remove_height(&mut self, height: u64) {
+        self.remove(HEIGHTS, height.to_string());
+    }
+
+    // 和读到的 tip 比较后再写入，避免另一个写入者在这期间修改了链
+    pub fn expect_tip(&mut self, hash: &str) {
+        self.expected_tip = Some(hash.into());
+    }
+
+    pub fn put_utxo(&mut self, txid: &str, vout: isize, coin: &Coin) -> Result<()> {
+        self.insert(
+            CHAINSTATE,
+            outpoint_key(txid, vout),
+            serde_json::to_string(coin)?,
+        );
+        Ok(())
+    }
+
+    pub fn remove_utxo(&mut self, txid: &str, vout: isize) {
+        self.remove(CHAINSTATE, outpoint_key(txid, vout));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    fn trees(&self) -> Vec<&'static str> {
+        let mut trees = vec![];
+        if self.expected_tip.is_some() {
+            trees.push(BLOCKS);
+        }
+        for op in self.ops.iter() {
+            let tree = match op {
+                BatchOp::Insert { tree, .. } => tree,
+                BatchOp::Remove { tree, .. } => tree,
+                BatchOp::Clear { tree } => tree,
+            };
+            if !trees.contains(tree) {
+                trees.push(*tree);
+            }
+        }
+        trees
+    }
+}
+
+// 区块、索引和链状态的存储
+pub trait ChainStore: Send + Sync + Debug {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    // 按 key 排序返回 tree 中的全部数据
+    fn scan(&self, tree: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    // 存储中所有 tree 的名字，用于导出检查点
+    fn tree_names(&self) -> Result<Vec<String>>;
+
+    // 原子地写入一组修改，要么全部成功，要么全部失败；
+    // 设置了 expect_tip 时，tip 不一致返回错误，不做任何修改
+    fn apply(&self, batch: Batch) -> Result<()>;
+
+    fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        match self.get(BLOCKS, hash.as_bytes())? {
+            Some(data) => Ok(Some(Block::deserialize(from_utf8(&data)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    // 区块数据被裁剪时使用保留的区块头
+    fn get_header(&self, hash: &str) -> Result<Option<BlockHeader>> {
+        if let Some(block) = self.get_block(hash)? {
+            return Ok(Some(block.header()?));
+        }
+        match self.get(HEADERS, hash.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // 当前链上这个高度的区块hash
+    fn get_hash_at(&self, height: u64) -> Result<Option<String>> {
+        match self.get(HEIGHTS, height.to_string().as_bytes())? {
+            Some(data) => Ok(Some(from_utf8(&data)?.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn is_pruned(&self, hash: &str) -> Result<bool> {
+        Ok(self.get(HEADERS, hash.as_bytes())?.is_some())
+    }
+
+    // 被裁剪的最高区块的高度，没有裁剪过时返回 None
+    fn get_prune_height(&self) -> Result<Option<u64>> {
+        match self.get(HEADERS, PRUNE_HEIGHT.as_bytes())? {
+            Some(data) => Ok(Some(from_utf8(&data)?.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        match self.get(BLOCKS, LAST.as_bytes())? {
+            Some(data) => Ok(Some(from_utf8(&data)?.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn get_utxo(&self, txid: &str, vout: isize) -> Result<Option<Coin>> {
+        match self.get(CHAINSTATE, outpoint_key(txid, vout).as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn utxos(&self) -> Result<Vec<((String, isize), Coin)>> {
+        let mut utxos = vec![];
+        for (key, value) in self.scan(CHAINSTATE)? {
+            let outpoint = parse_outpoint_key(from_utf8(&key)?)?;
+            utxos.push((outpoint, serde_json::from_slice(&value)?));
+        }
+        Ok(utxos)
+    }
+}
+
+pub fn outpoint_key(txid: &str, vout: isize) -> String {
+    format!("{txid}:{vout}")
+}
+
+pub fn parse_outpoint_key(key: &str) -> Result<(String, isize)> {
+    let (txid, vout) = key
+        .split_once(':')
+        .ok_or(anyhow!("Invalid outpoint key {key}"))?;
+    Ok((txid.into(), vout.parse()?))
+}
+
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            db: open_db(path.as_ref())?,
+        })
+    }
+}
+
+// 进程间已经由数据目录的锁互斥，sled 的文件锁被占用只可能是刚关闭的数据库的
+// 后台线程还没有退出，稍等后重试
+fn open_db(path: &Path) -> Result<sled::Db> {
+    let mut retries = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") => {
+                if retries == OPEN_RETRIES {
+                    return Err(e.into());
+                }
+                retries += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            r => return Ok(r?),
+        }
+    }
+}
+
+impl ChainStore for SledStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tree = self.db.open_tree(tree)?;
+        Ok(tree.get(key)?.map(|iv| iv.to_vec()))
+    }
+
+    fn scan(&self, tree: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree = self.db.open_tree(tree)?;
+        let mut data = vec![];
+        for r in tree.iter() {
+            let (key, value) = r?;
+            data.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(data)
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for name in self.db.tree_names() {
+            // sled 的默认 tree 不保存数据
+            if name != self.db.name() {
+                names.push(from_utf8(&name)?.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn apply(&self, batch: Batch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let names = batch.trees();
+        let mut trees = vec![];
+        // sled 的事务不能清空 tree，需要先读出所有的 key 在事务中逐个删除
+        let mut cleared: Vec<Vec<Vec<u8>>> = vec![];
+        for name in names.iter() {
+            let tree = self.db.open_tree(name)?;
+            let clear = batch
+                .ops()
+                .iter()
+                .any(|op| matches!(op, BatchOp::Clear { tree } if tree == name));
+            let mut keys = vec![];
+            if clear {
+                for r in tree.iter().keys() {
+                    keys.push(r?.to_vec());
+                }
+            }
+            cleared.push(keys);
+            trees.push(tree);
+        }
+
+        let r: Result<(), TransactionError<anyhow::Error>> = trees[..].transaction(|views| {
+            if let Some(expected) = batch.expected_tip.as_deref() {
+                let idx = names.iter().position(|n| *n == BLOCKS).unwrap_or_default();
+                let tip = views[idx].get(LAST)?;
+                if tip.as_deref() != Some(expected.as_bytes()) {
+                    return Err(ConflictableTransactionError::Abort(tip_changed()));
+                }
+            }
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Insert { tree, key, value } => {
+                        let idx = names.iter().position(|n| n == tree).unwrap_or_default();
+                        views[idx].insert(key.as_slice(), value.as_slice())?;
+                    }
+                    BatchOp::Remove { tree, key } => {
+                        let idx = names.iter().position(|n| n == tree).unwrap_or_default();
+                        views[idx].remove(key.as_slice())?;
+                    }
+                    BatchOp::Clear { tree } => {
+                        let idx = names.iter().position(|n| n == tree).unwrap_or_default();
+                        for key in cleared[idx].iter() {
+                            views[idx].remove(key.as_slice())?;
+                        }
+                    }
+                }
+            }
+            Ok::<(), ConflictableTransactionError<anyhow::Error>>(())
+        });
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+}
+
+fn tip_changed() -> anyhow::Error {
+    anyhow!("Tip changed by another writer, try again")
+}
+
+type MemoryTree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// tree 名字 => hex 编码的 key 和 value
+type Checkpoint = BTreeMap<String, Vec<(String, String)>>;
+
+// 写入进程在没有其他修改时导出存储的全部数据，先写临时文件再替换，
+// 读取检查点的进程不会看到写了一半的文件，也不需要打开 sled 数据库
+pub fn write_checkpoint(store: &dyn ChainStore, path: &Path) -> Result<()> {
+    let mut checkpoint = Checkpoint::new();
+    for name in store.tree_names()? {
+        let entries = store
+            .scan(&name)?
+            .into_iter()
+            .map(|(key, value)| (hex::encode(key), hex::encode(value)))
+            .collect();
+        checkpoint.insert(name, entries);
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(&checkpoint)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// 数据只保存在内存中，用于测试和模拟
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    trees: Mutex<BTreeMap<String, MemoryTree>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 读入 write_checkpoint 导出的数据
+    pub fn load_checkpoint(path: impl AsRef<Path>) -> Result<Self> {
+        let checkpoint: Checkpoint = serde_json::from_slice(&fs::read(path)?)?;
+        let mut trees = BTreeMap::new();
+        for (name, entries) in checkpoint {
+            let mut tree = MemoryTree::new();
+            for (key, value) in entries {
+                tree.insert(hex::decode(key)?, hex::decode(value)?);
+            }
+            trees.insert(name, tree);
+        }
+        Ok(Self {
+            trees: Mutex::new(trees),
+        })
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.lock().map_err(|e| anyhow!("{e}"))?;
+        Ok(trees.get(tree).and_then(|t| t.get(key)).cloned())
+    }
+
+    fn scan(&self, tree: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let trees = self.trees.lock().map_err(|e| anyhow!("{e}"))?;
+        Ok(trees
+            .get(tree)
+            .map(|t| t.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let trees = self.trees.lock().map_err(|e| anyhow!("{e}"))?;
+        Ok(trees.keys().cloned().collect())
+    }
+
+    fn apply(&self, batch: Batch) -> Result<()> {
+        // 持有锁的期间整体修改，对其他读者来说是原子的
+        let mut trees = self.trees.lock().map_err(|e| anyhow!("{e}"))?;
+        if let Some(expected) = batch.expected_tip.as_deref() {
+            let tip = trees.get(BLOCKS).and_then(|t| t.get(LAST.as_bytes()));
+            if tip.map(|t| t.as_slice()) != Some(expected.as_bytes()) {
+                return Err(tip_changed());
+            }
+        }
+        let snapshot = trees.clone();
+        for op in batch.ops {
+            match op {
+                BatchOp::Insert { tree, key, value } => {
+                    trees.entry(tree.into()).or_default().insert(key, value);
+                }
+                BatchOp::Remove { tree, key } => {
+                    trees.entry(tree.into()).or_default().remove(&key);
+                }
+                BatchOp::Clear { tree } => {
+                    let t = trees.entry(tree.into()).or_default();
+                    for key in snapshot.get(tree).into_iter().flat_map(|t| t.keys()) {
+                        t.remove(key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{blockchain::Blockchain, config::Config, testutil::new_chain, utxoset::UTXOSet};
+
+    use super::{Batch, ChainStore, MemoryStore, CHAINSTATE, MEMPOOL};
+
+    #[test]
+    fn test_batch_clear_keeps_later_writes() {
+        let store = MemoryStore::new();
+        let mut batch = Batch::default();
+        batch.insert(CHAINSTATE, "a", "1");
+        batch.insert(CHAINSTATE, "b", "2");
+        store.apply(batch).unwrap();
+
+        let mut batch = Batch::default();
+        batch.clear(CHAINSTATE);
+        batch.insert(CHAINSTATE, "b", "3");
+        store.apply(batch).unwrap();
+
+        assert_eq!(
+            store.scan(CHAINSTATE).unwrap(),
+            vec![(b"b".to_vec(), b"3".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_expect_tip() {
+        let store = MemoryStore::new();
+        let mut batch = Batch::default();
+        batch.set_tip("a");
+        store.apply(batch).unwrap();
+
+        // tip 不一致时整个 batch 都不写入
+        let mut batch = Batch::default();
+        batch.expect_tip("b");
+        batch.insert(MEMPOOL, "tx", "1");
+        batch.set_tip("c");
+        assert!(store.apply(batch).is_err());
+        assert_eq!(store.get_tip().unwrap().as_deref(), Some("a"));
+        assert!(store.scan(MEMPOOL).unwrap().is_empty());
+
+        let mut batch = Batch::default();
+        batch.expect_tip("a");
+        batch.set_tip("c");
+        store.apply(batch).unwrap();
+        assert_eq!(store.get_tip().unwrap().as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn test_memory_chain() {
+        let (wallet, store, bc, _) = new_chain(Config::default());
+        let pubkey_hash = hex::encode(crate::wallet::hash_pubkey(&wallet.public_key));
+
+        let utxoset = UTXOSet::new(bc.clone());
+        assert_eq!(utxoset.find_utxo(&pubkey_hash).unwrap()[0].value, 50);
+
+        let reopened = Blockchain::open(store, Config::default()).unwrap();
+        assert_eq!(reopened.tip, bc.tip);
+        assert_eq!(reopened.get_best_height().unwrap(), 0);
+        assert!(reopened.get_datadir().is_err());
+    }
+}
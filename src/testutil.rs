@@ -0,0 +1,71 @@
+// 测试中共用的链和交易构造函数
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    blockchain::Blockchain,
+    config::Config,
+    store::MemoryStore,
+    transaction::{Transaction, TxInput, TxOutput},
+    wallet::Wallet,
+};
+
+// 在内存中创建创世块支付给 address 的链，返回存储、链和创世块的 coinbase 交易，
+// 创世块的 UTXO 在创建时已经写入
+pub fn chain_to(address: String, config: Config) -> (Arc<MemoryStore>, Blockchain, Transaction) {
+    let store = Arc::new(MemoryStore::new());
+    let bc = Blockchain::create(store.clone(), address, config).unwrap();
+    let coinbase = bc.iterator().next().unwrap().unwrap().transactions[0].clone();
+    (store, bc, coinbase)
+}
+
+// 创世块支付给一个新钱包
+pub fn new_chain(config: Config) -> (Wallet, Arc<MemoryStore>, Blockchain, Transaction) {
+    let wallet = Wallet::new_wallet();
+    let (store, bc, coinbase) = chain_to(wallet.get_address(), config);
+    (wallet, store, bc, coinbase)
+}
+
+// wallet 花费 prev 的第 vout 个输出，返回签好名的交易
+pub fn spend(
+    wallet: &Wallet,
+    prev: &Transaction,
+    vout: isize,
+    outputs: Vec<TxOutput>,
+) -> Transaction {
+    let tx = Transaction {
+        id: String::new(),
+        vin: vec![TxInput {
+            txid: prev.id.clone(),
+            vout,
+            pubkey: wallet.public_key.clone(),
+            ..Default::default()
+        }],
+        vout: outputs,
+    };
+    sign(wallet, prev, tx)
+}
+
+// 修改过的交易重新计算 id 并签名，所有输入都引用 prev
+pub fn sign(wallet: &Wallet, prev: &Transaction, mut tx: Transaction) -> Transaction {
+    tx.set_id().unwrap();
+    tx.sign(
+        &wallet.secret_key,
+        HashMap::from([(prev.id.clone(), prev.clone())]),
+    )
+    .unwrap();
+    tx
+}
+
+// 所有输出都回到 wallet 自己的地址
+pub fn spend_to_self(
+    wallet: &Wallet,
+    prev: &Transaction,
+    vout: isize,
+    values: Vec<isize>,
+) -> Transaction {
+    let outputs = values
+        .into_iter()
+        .map(|v| TxOutput::new_tx_output(v, &wallet.address()))
+        .collect();
+    spend(wallet, prev, vout, outputs)
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 
 use anyhow::anyhow;
@@ -7,16 +7,24 @@ use base58::FromBase58;
 use ecdsa::{signature::Signer, signature::Verifier, Signature, SigningKey, VerifyingKey};
 use p256::NistP256;
 use serde::{Deserialize, Serialize};
-use tracing::error;
+use tracing::{error, warn};
 
+use crate::address::Address;
+use crate::address::AddressEncoding;
 use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::scheme::SchemeTag;
 use crate::utxoset;
 use crate::utxoset::UTXOSet;
 use crate::wallet::hash_pubkey;
-use crate::wallet::pubkey_hash_from_base58;
+use crate::wallet::Wallet;
 use crate::wallet::Wallets;
 
 const SUBSIDY: isize = 50;
+// 默认的输入序号，表示不可替换
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+// 序号不大于这个值的输入表示交易可以被更高手续费的交易替换 (BIP125)
+pub const MAX_RBF_SEQUENCE: u32 = 0xfffffffd;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Transaction {
@@ -25,12 +33,33 @@ pub struct Transaction {
     pub vout: Vec<TxOutput>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TxInput {
     pub txid: String, // 引用的交易
     pub vout: isize,  // 引用的交易中，输出的索引
     pub signature: String,
     pub pubkey: Vec<u8>, // 原始公钥，未hash
+    #[serde(default)]
+    pub preimage: String, // 花费HTLC输出时公开的原像(hex)，为空表示走退款分支
+    #[serde(default = "default_sequence")]
+    pub sequence: u32,
+}
+
+impl Default for TxInput {
+    fn default() -> Self {
+        Self {
+            txid: String::new(),
+            vout: 0,
+            signature: String::new(),
+            pubkey: Vec::new(),
+            preimage: String::new(),
+            sequence: SEQUENCE_FINAL,
+        }
+    }
+}
+
+fn default_sequence() -> u32 {
+    SEQUENCE_FINAL
 }
 
 impl TxInput {
@@ -40,21 +69,65 @@ impl TxInput {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct TxOutput {
     pub value: isize,
     pub pubkey_hash: String,
+    #[serde(default)]
+    pub scheme: SchemeTag, // 解锁此输出的公钥所使用的签名算法
+    #[serde(default)]
+    pub htlc: Option<Htlc>, // 哈希时间锁，存在时 pubkey_hash 为空
+}
+
+// 哈希时间锁合约：接收方公开 hash_lock 的原像并签名即可花费，
+// 或者在区块高度达到 timeout 之后由退款方签名取回
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Htlc {
+    pub hash_lock: String, // sha256(preimage)
+    pub receiver_pubkey_hash: String,
+    pub receiver_scheme: SchemeTag,
+    pub refund_pubkey_hash: String,
+    pub refund_scheme: SchemeTag,
+    pub timeout: u64, // 从这个高度开始可以退款
 }
 
 impl TxOutput {
-    pub fn new_tx_output(value: isize, address: String) -> Result<Self> {
+    pub fn new_tx_output(value: isize, address: &Address) -> Self {
         let mut out = Self {
             value,
-            pubkey_hash: Default::default(),
+            ..Default::default()
         };
 
-        out.lock(address.as_str())?;
-        Ok(out)
+        out.lock(address);
+        out
+    }
+
+    pub fn new_htlc_output(
+        value: isize,
+        receiver: &Address,
+        refund: &Address,
+        hash_lock: String,
+        timeout: u64,
+    ) -> Result<Self> {
+        if hex::decode(hash_lock.as_str()).map(|h| h.len()) != Ok(32) {
+            return Err(anyhow!("Hash lock must be a hex encoded sha256"));
+        }
+
+        // 和 sha256::digest 的输出一样使用小写，否则大写输入的 hash lock 永远无法被领取
+        let htlc = Htlc {
+            hash_lock: hash_lock.to_lowercase(),
+            receiver_pubkey_hash: receiver.pubkey_hash(),
+            receiver_scheme: receiver.scheme(),
+            refund_pubkey_hash: refund.pubkey_hash(),
+            refund_scheme: refund.scheme(),
+            timeout,
+        };
+
+        Ok(Self {
+            value,
+            htlc: Some(htlc),
+            ..Default::default()
+        })
     }
 }
 
@@ -63,30 +136,252 @@ impl TxOutput {
         self.pubkey_hash == pubkey_hash
     }
 
-    pub fn lock(&mut self, address: &str) -> Result<()> {
-        self.pubkey_hash = pubkey_hash_from_base58(address)?;
-        Ok(())
+    pub fn lock(&mut self, address: &Address) {
+        self.pubkey_hash = address.pubkey_hash();
+        self.scheme = address.scheme();
+    }
+
+    // 输出的收款方，HTLC 输出算作接收方的
+    pub fn receiver(&self) -> (&str, SchemeTag) {
+        match &self.htlc {
+            Some(htlc) => (&htlc.receiver_pubkey_hash, htlc.receiver_scheme),
+            None => (&self.pubkey_hash, self.scheme),
+        }
+    }
+
+    // 花费此输出的输入需要提供的公钥hash和签名算法，HTLC 根据是否提供原像选择分支
+    pub fn spending_key(&self, vin: &TxInput) -> (String, SchemeTag) {
+        match &self.htlc {
+            Some(htlc) if !vin.preimage.is_empty() => {
+                (htlc.receiver_pubkey_hash.clone(), htlc.receiver_scheme)
+            }
+            Some(htlc) => (htlc.refund_pubkey_hash.clone(), htlc.refund_scheme),
+            None => (self.pubkey_hash.clone(), self.scheme),
+        }
+    }
+
+    // 检查输入是否满足 HTLC 的原像或超时条件，height 是交易所在区块的高度
+    pub fn check_htlc(&self, vin: &TxInput, height: u64) -> Result<bool> {
+        let Some(htlc) = &self.htlc else {
+            return Ok(vin.preimage.is_empty());
+        };
+
+        if vin.preimage.is_empty() {
+            return Ok(height >= htlc.timeout);
+        }
+
+        let preimage = hex::decode(vin.preimage.as_str())?;
+        Ok(sha256::digest(preimage) == htlc.hash_lock)
+    }
+}
+
+// 一次签名验证所需要的全部数据，可以脱离交易在其他线程中执行
+#[derive(Debug, Clone)]
+pub struct SigCheck {
+    pub scheme: SchemeTag,
+    pub sighash: String,
+    pub pubkey: Vec<u8>,
+    pub signature: String,
+}
+
+impl SigCheck {
+    // 不经过缓存直接验证，见 SigCache::verify
+    pub fn verify(&self) -> bool {
+        let res = (|| -> Result<()> {
+            let msg = hex::decode(self.sighash.as_str())?;
+            let signature = hex::decode(self.signature.as_str())?;
+            self.scheme
+                .scheme()
+                .verify(self.pubkey.as_slice(), msg.as_slice(), signature.as_slice())
+        })();
+
+        match res {
+            Ok(_) => true,
+            Err(e) => {
+                println!("verfiying_key err: {e}");
+                false
+            }
+        }
     }
 }
 
 impl Transaction {
+    // fee 从找零中扣除，replaceable 表示交易在确认前可以被 bumpfee 替换
     pub fn new_utxo_transaction(
-        from: String,
-        to: String,
+        from: &Address,
+        to: &Address,
+        amount: isize,
+        fee: isize,
+        replaceable: bool,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let output = TxOutput::new_tx_output(amount, to);
+        Self::new_funded_transaction(from, output, fee, replaceable, bc)
+    }
+
+    // 用更高的手续费重新构造交易池中的一笔交易：保留原交易的输入和付款输出，从找零中扣除
+    // 增加的手续费，找零不够时再从第一个输入的地址补充输入
+    pub fn new_bumpfee_transaction(
+        txid: &str,
+        fee: Option<isize>,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let mempool = Mempool::new(bc.clone());
+        let Some(orig) = mempool.get(txid)? else {
+            return Err(anyhow!("Transaction {txid} is not in the mempool"));
+        };
+        if !orig.signals_rbf() {
+            return Err(anyhow!("Transaction {txid} is not replaceable"));
+        }
+
+        let orig_fee = mempool.fee(&orig)?;
+        let fee = fee.unwrap_or(orig_fee + 1);
+        if fee <= orig_fee {
+            return Err(anyhow!("New fee {fee} must be higher than {orig_fee}"));
+        }
+
+        let mut wallets = Wallets::new_wallets(bc.get_datadir()?)?;
+        // 每个输入由花费的输出对应的钱包密钥签名
+        let mut signers = vec![];
+        for vin in orig.vin.iter() {
+            let prev_out = bc.find_output(&vin.txid, vin.vout)?;
+            let (pubkey_hash, scheme) = prev_out.spending_key(vin);
+            let address = Address::new(&hex::decode(pubkey_hash)?, scheme)?;
+            let wallet = wallets.get_wallet(&address.to_string())?;
+            if !signers
+                .iter()
+                .any(|w: &Wallet| w.public_key == wallet.public_key)
+            {
+                signers.push(wallet);
+            }
+        }
+
+        let mut vin: Vec<TxInput> = orig
+            .vin
+            .iter()
+            .map(|vin| TxInput {
+                signature: String::new(),
+                ..vin.clone()
+            })
+            .collect();
+        let (change, mut vout): (Vec<TxOutput>, Vec<TxOutput>) = orig
+            .vout
+            .into_iter()
+            .partition(|out| wallets.is_change(out));
+        let mut change_value: isize = change.iter().map(|out| out.value).sum();
+        change_value -= fee - orig_fee;
+
+        if change_value < 0 {
+            let signer = signers[0].clone();
+            let exclude: HashSet<(String, isize)> =
+                mempool.spent_outpoints()?.into_keys().collect();
+            let pubkey_hash = hex::encode(hash_pubkey(&signer.public_key));
+            let (acc, outputs) = UTXOSet::new(bc.clone()).find_spentable_outputs(
+                &pubkey_hash,
+                -change_value,
+                &exclude,
+            )?;
+            if acc < -change_value {
+                return Err(anyhow!("Error: Not enough funds"));
+            }
+            let pubkey = signer.scheme.scheme().encode_pubkey(&signer.public_key)?;
+            for (txid, outs) in outputs {
+                for out in outs {
+                    vin.push(TxInput {
+                        txid: txid.clone(),
+                        vout: out,
+                        pubkey: pubkey.clone(),
+                        sequence: MAX_RBF_SEQUENCE,
+                        ..Default::default()
+                    });
+                }
+            }
+            change_value += acc;
+        }
+        if change_value > 0 {
+            let address = match change.first() {
+                Some(out) => wallets.output_address(out)?,
+                None => wallets
+                    .get_change_address(AddressEncoding::default())?
+                    .parse()?,
+            };
+            vout.push(TxOutput::new_tx_output(change_value, &address));
+        }
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout,
+        };
+        tx.set_id()?;
+        let prev_txs = bc.prev_transactions(std::slice::from_ref(&tx))?;
+        for signer in signers.iter() {
+            let pubkey = signer.scheme.scheme().encode_pubkey(&signer.public_key)?;
+            tx.sign_inputs_of(&pubkey, &signer.secret_key, &prev_txs)?;
+        }
+        wallets.save_to_file()?;
+
+        // 替换交易必须和原交易冲突，否则两笔交易都会被接受，收款方收到两次
+        let original: HashSet<(&str, isize)> =
+            orig.vin.iter().map(|v| (v.txid.as_str(), v.vout)).collect();
+        if !tx
+            .vin
+            .iter()
+            .any(|v| original.contains(&(v.txid.as_str(), v.vout)))
+        {
+            return Err(anyhow!("Replacement does not spend any input of {txid}"));
+        }
+        Ok(tx)
+    }
+
+    // 锁定 amount 到一个HTLC输出，from 同时作为退款方
+    pub fn new_htlc_transaction(
+        from: &Address,
+        receiver: &Address,
         amount: isize,
+        hash_lock: String,
+        timeout: u64,
         bc: &Blockchain,
     ) -> Result<Transaction> {
+        let output = TxOutput::new_htlc_output(amount, receiver, from, hash_lock, timeout)?;
+        Self::new_funded_transaction(from, output, 0, false, bc)
+    }
+
+    // 用 from 的未花费输出支付 output 和手续费，多余的部分找零到钱包新的找零地址
+    // 交易池中已经被花费的输出不会再被选中
+    fn new_funded_transaction(
+        from: &Address,
+        output: TxOutput,
+        fee: isize,
+        replaceable: bool,
+        bc: &Blockchain,
+    ) -> Result<Self> {
+        if fee < 0 {
+            return Err(anyhow!("Fee can not be negative"));
+        }
+        let amount = output.value + fee;
         let mut inputs: Vec<TxInput> = vec![];
-        let mut outputs = vec![];
+        let mut outputs = vec![output];
+        let sequence = if replaceable {
+            MAX_RBF_SEQUENCE
+        } else {
+            SEQUENCE_FINAL
+        };
+
+        let exclude: HashSet<(String, isize)> = Mempool::new(bc.clone())
+            .spent_outpoints()?
+            .into_keys()
+            .collect();
 
-        let wallets = Wallets::new_wallets()?;
-        let wallet = wallets.get_wallet(from.as_str())?;
+        let mut wallets = Wallets::new_wallets(bc.get_datadir()?)?;
+        let wallet = wallets.get_wallet(&from.to_string())?;
         let pubkey_hash = hash_pubkey(&wallet.public_key);
 
+        let pubkey = wallet.scheme.scheme().encode_pubkey(&wallet.public_key)?;
         let utxoset = UTXOSet::new(bc.clone());
 
         let (acc, valid_outputs) =
-            utxoset.find_spentable_outputs(hex::encode(pubkey_hash).as_str(), amount)?;
+            utxoset.find_spentable_outputs(hex::encode(pubkey_hash).as_str(), amount, &exclude)?;
 
         println!("-------------acc:{acc}----------------------");
 
@@ -100,19 +395,18 @@ impl Transaction {
                 .map(|out| TxInput {
                     txid: txid.clone(),
                     vout: out,
-                    signature: String::new(),
-                    pubkey: wallet.public_key.clone(),
+                    pubkey: pubkey.clone(),
+                    sequence,
+                    ..Default::default()
                 })
                 .collect();
             inputs.extend(input);
         }
 
-        outputs.push(TxOutput::new_tx_output(amount, to)?);
-
         // 找零
         if acc > amount {
-            let other_output = TxOutput::new_tx_output(acc - amount, from)?;
-            outputs.push(other_output);
+            let change: Address = wallets.get_change_address(from.encoding())?.parse()?;
+            outputs.push(TxOutput::new_tx_output(acc - amount, &change));
         }
 
         let mut tx = Transaction {
@@ -123,6 +417,63 @@ impl Transaction {
 
         tx.set_id()?;
 
+        bc.sign_transaction(&mut tx, wallet.secret_key.as_slice())?;
+        wallets.save_to_file()?;
+
+        Ok(tx)
+    }
+
+    // 花费一个HTLC输出：提供 preimage 时走接收方分支，否则走超时退款分支
+    pub fn new_htlc_spend_transaction(
+        txid: String,
+        vout: isize,
+        to: Option<Address>,
+        preimage: Option<Vec<u8>>,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let prev_out = bc.find_output(&txid, vout)?;
+        let Some(htlc) = &prev_out.htlc else {
+            return Err(anyhow!("Output {txid}:{vout} is not a HTLC output"));
+        };
+
+        let (pubkey_hash, scheme) = match &preimage {
+            Some(p) => {
+                if sha256::digest(p.as_slice()) != htlc.hash_lock {
+                    return Err(anyhow!("Preimage does not match the hash lock"));
+                }
+                (htlc.receiver_pubkey_hash.clone(), htlc.receiver_scheme)
+            }
+            None => {
+                let height = bc.get_best_height()? + 1;
+                if height < htlc.timeout {
+                    return Err(anyhow!(
+                        "HTLC can be refunded from height {}, next block is {height}",
+                        htlc.timeout
+                    ));
+                }
+                (htlc.refund_pubkey_hash.clone(), htlc.refund_scheme)
+            }
+        };
+
+        let address = Address::new(&hex::decode(pubkey_hash)?, scheme)?;
+        let wallets = Wallets::new_wallets(bc.get_datadir()?)?;
+        let wallet = wallets.get_wallet(&address.to_string())?;
+        let to = to.unwrap_or(address);
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput {
+                txid,
+                vout,
+                pubkey: scheme.scheme().encode_pubkey(&wallet.public_key)?,
+                preimage: preimage.map(hex::encode).unwrap_or_default(),
+                ..Default::default()
+            }],
+            vout: vec![TxOutput::new_tx_output(prev_out.value, &to)],
+        };
+
+        tx.set_id()?;
+
         bc.sign_transaction(&mut tx, wallet.secret_key.as_slice())?;
 
         Ok(tx)
@@ -136,11 +487,11 @@ impl Transaction {
         let txin = TxInput {
             txid: String::new(),
             vout: -1,
-            signature: String::new(),
             pubkey: data.into_bytes(),
+            ..Default::default()
         };
 
-        let txout = TxOutput::new_tx_output(SUBSIDY, to)?;
+        let txout = TxOutput::new_tx_output(SUBSIDY, &to.parse()?);
 
         let mut tx = Transaction {
             id: String::new(),
@@ -156,54 +507,104 @@ impl Transaction {
 
 impl Transaction {
     pub fn sign(&mut self, privkey: &[u8], prev_txs: HashMap<String, Transaction>) -> Result<()> {
+        self.sign_matching(None, privkey, &prev_txs)
+    }
+
+    // 只签名公钥为 pubkey 的输入，输入来自多个密钥时每个密钥分别签名
+    pub fn sign_inputs_of(
+        &mut self,
+        pubkey: &[u8],
+        privkey: &[u8],
+        prev_txs: &HashMap<String, Transaction>,
+    ) -> Result<()> {
+        self.sign_matching(Some(pubkey), privkey, prev_txs)
+    }
+
+    fn sign_matching(
+        &mut self,
+        pubkey: Option<&[u8]>,
+        privkey: &[u8],
+        prev_txs: &HashMap<String, Transaction>,
+    ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
-        let mut tx_copy = self.trimmed_copy();
-
-        let signing_key: SigningKey<NistP256> = SigningKey::from_slice(privkey)?;
         for (in_id, vin) in self.vin.clone().iter().enumerate() {
+            if pubkey.is_some_and(|pubkey| pubkey != vin.pubkey.as_slice()) {
+                continue;
+            }
             if let Some(prev_tx) = prev_txs.get(&vin.txid) {
-                let pubkey = prev_tx.vout[vin.vout as usize].pubkey_hash.clone();
-                tx_copy.vin[in_id].pubkey = pubkey.into();
-                tx_copy.id = tx_copy.hash()?;
-                tx_copy.vin[in_id].pubkey = Vec::new();
-
-                let msg = hex::decode(tx_copy.id.as_str())?;
-                let signature: Signature<NistP256> = signing_key.try_sign(msg.as_slice())?;
-                self.vin[in_id].signature = hex::encode(signature.to_vec());
+                let (pubkey_hash, scheme) = prev_tx.vout[vin.vout as usize].spending_key(vin);
+                let msg = hex::decode(self.sighash(in_id, pubkey_hash.as_str())?)?;
+                let signature = scheme.scheme().sign(privkey, msg.as_slice())?;
+                self.vin[in_id].signature = hex::encode(signature);
             }
         }
 
         Ok(())
     }
 
-    pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
-        let mut tx_copy = self.trimmed_copy();
+    // height 为交易所在（或将要打包进的）区块高度，用于判断HTLC是否超时
+    pub fn verify(&self, prev_txs: HashMap<String, Transaction>, height: u64) -> Result<bool> {
+        match self.signature_checks(&prev_txs, height)? {
+            Some(checks) => Ok(checks.iter().all(|c| c.verify())),
+            None => Ok(false),
+        }
+    }
+
+    // 收集交易中每个输入需要验证的签名，交易本身不合法时返回 None
+    pub fn signature_checks(
+        &self,
+        prev_txs: &HashMap<String, Transaction>,
+        height: u64,
+    ) -> Result<Option<Vec<SigCheck>>> {
+        // 交易id只覆盖非签名数据，签名被替换后id不变，但是id本身必须和内容一致
+        if self.id != self.txid()? {
+            warn!("Transaction id mismatch: {}", self.id);
+            return Ok(None);
+        }
+
+        if self.is_coinbase() {
+            return Ok(Some(vec![]));
+        }
 
+        let mut checks = Vec::with_capacity(self.vin.len());
         for (in_id, vin) in self.vin.iter().enumerate() {
-            if let Some(prev_tx) = prev_txs.get(&vin.txid) {
-                let pubkey = prev_tx.vout[vin.vout as usize].pubkey_hash.clone();
-                tx_copy.vin[in_id].pubkey = pubkey.into();
-                tx_copy.id = tx_copy.hash()?;
-                tx_copy.vin[in_id].pubkey = Vec::new();
-
-                let msg = hex::decode(tx_copy.id.as_str())?;
-                let verfiying_key: VerifyingKey<NistP256> =
-                    VerifyingKey::from_sec1_bytes(vin.pubkey.as_slice())?;
-
-                let signature: Signature<NistP256> = Signature::from_str(vin.signature.as_str())?;
-                if let Err(e) = verfiying_key.verify(msg.as_slice(), &signature) {
-                    println!("verfiying_key err: {e}");
-                    return Ok(false);
+            match prev_txs.get(&vin.txid) {
+                Some(prev_tx) => {
+                    let Some(prev_out) = prev_tx.vout.get(vin.vout as usize) else {
+                        return Ok(None);
+                    };
+                    let (pubkey_hash, scheme) = prev_out.spending_key(vin);
+                    if !vin.uses_key(pubkey_hash.as_str()) {
+                        warn!("Input pubkey does not match output: {}", self.id);
+                        return Ok(None);
+                    }
+                    if !prev_out.check_htlc(vin, height)? {
+                        warn!("HTLC condition not satisfied: {}", self.id);
+                        return Ok(None);
+                    }
+                    checks.push(SigCheck {
+                        scheme,
+                        sighash: self.sighash(in_id, pubkey_hash.as_str())?,
+                        pubkey: vin.pubkey.clone(),
+                        signature: vin.signature.clone(),
+                    });
                 }
-            } else {
-                return Ok(false);
+                None => return Ok(None),
             }
         }
 
-        Ok(true)
+        Ok(Some(checks))
+    }
+
+    // 第in_id个输入需要签名的数据：去掉所有签名和公钥，只在当前输入中放入被引用输出的公钥hash
+    pub fn sighash(&self, in_id: usize, prev_pubkey_hash: &str) -> Result<String> {
+        let mut tx_copy = self.trimmed_copy();
+        tx_copy.id = String::new();
+        tx_copy.vin[in_id].pubkey = prev_pubkey_hash.into();
+        tx_copy.hash()
     }
 
     pub fn trimmed_copy(&self) -> Self {
@@ -214,25 +615,36 @@ impl Transaction {
             .map(|v| TxInput {
                 txid: v.txid,
                 vout: v.vout,
+                sequence: v.sequence,
                 ..Default::default()
             })
             .collect();
 
-        let outputs: Vec<TxOutput> = self
-            .vout
-            .clone()
-            .into_iter()
-            .map(|v| TxOutput {
-                value: v.value,
-                pubkey_hash: v.pubkey_hash,
-            })
-            .collect();
-
         Self {
             id: self.id.clone(),
             vin: inputs,
-            vout: outputs,
+            vout: self.vout.clone(),
+        }
+    }
+
+    // 任意一个输入的序号不大于 MAX_RBF_SEQUENCE，即表示允许被替换
+    pub fn signals_rbf(&self) -> bool {
+        self.vin.iter().any(|v| v.sequence <= MAX_RBF_SEQUENCE)
+    }
+
+    // 输入总额减去输出总额
+    pub fn fee(&self, prev_txs: &HashMap<String, Transaction>) -> Result<isize> {
+        let mut input_value = 0;
+        for vin in self.vin.iter() {
+            let prev_out = prev_txs
+                .get(&vin.txid)
+                .and_then(|tx| tx.vout.get(vin.vout as usize))
+                .ok_or(anyhow!("Input {}:{} not found", vin.txid, vin.vout))?;
+            input_value += prev_out.value;
         }
+
+        let output_value: isize = self.vout.iter().map(|out| out.value).sum();
+        Ok(input_value - output_value)
     }
 
     pub fn is_coinbase(&self) -> bool {
@@ -240,10 +652,32 @@ impl Transaction {
     }
 
     pub fn set_id(&mut self) -> Result<()> {
-        self.id = self.hash()?;
+        self.id = self.txid()?;
         Ok(())
     }
 
+    // txid 不包含签名、公钥和原像（见证数据），签名前后计算结果一致，签名也无法改变交易的引用
+    // coinbase 的输入中 pubkey 存放的是附加数据，不属于见证数据，需要保留
+    pub fn txid(&self) -> Result<String> {
+        let mut tx_copy = self.clone();
+        tx_copy.id = String::new();
+        let is_coinbase = self.is_coinbase();
+        for vin in tx_copy.vin.iter_mut() {
+            vin.signature = String::new();
+            vin.preimage = String::new();
+            if !is_coinbase {
+                vin.pubkey = Vec::new();
+            }
+        }
+
+        tx_copy.hash()
+    }
+
+    // 见证hash覆盖交易的全部数据，包括签名和公钥
+    pub fn witness_hash(&self) -> Result<String> {
+        self.hash()
+    }
+
     pub fn hash(&self) -> Result<String> {
         let data = serde_json::to_string(self).map_err(|e| {
             error!("Serialize transaction err: {e}");
@@ -254,3 +688,207 @@ impl Transaction {
         Ok(hash)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use crate::{
+        address::{Address, AddressEncoding},
+        block::Block,
+        blockchain::Blockchain,
+        datadir::DataDir,
+        mempool::Mempool,
+        scheme::SchemeTag,
+        testutil,
+        wallet::{Wallet, Wallets},
+    };
+
+    use super::{Transaction, TxInput, TxOutput};
+
+    #[test]
+    fn test_txid_excludes_witness() {
+        let wallet = Wallet::new_wallet();
+        let address = wallet.get_address();
+        let coinbase = Transaction::new_coin_base_tx(address.clone(), String::new()).unwrap();
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput {
+                txid: coinbase.id.clone(),
+                vout: 0,
+                pubkey: wallet.public_key.clone(),
+                ..Default::default()
+            }],
+            vout: vec![TxOutput::new_tx_output(10, &address.parse().unwrap())],
+        };
+        tx.set_id().unwrap();
+        let unsigned_id = tx.id.clone();
+
+        let prev_txs = HashMap::from([(coinbase.id.clone(), coinbase)]);
+        tx.sign(&wallet.secret_key, prev_txs.clone()).unwrap();
+        assert_eq!(tx.id, unsigned_id);
+        assert_eq!(tx.id, tx.txid().unwrap());
+        assert!(tx.verify(prev_txs.clone(), 1).unwrap());
+
+        let mut tampered = tx.clone();
+        tampered.vin[0].signature = "00".repeat(64);
+        assert_eq!(tampered.txid().unwrap(), tx.id);
+        assert_ne!(tampered.witness_hash().unwrap(), tx.witness_hash().unwrap());
+
+        let mut forged = tx.clone();
+        forged.vout[0].value = 20;
+        assert!(!forged.verify(prev_txs, 1).unwrap());
+    }
+
+    #[test]
+    fn test_schemes_coexist_in_block() {
+        let ecdsa_wallet = Wallet::new_wallet();
+        let test_wallet = Wallet::new_wallet_with_scheme(SchemeTag::Test);
+        assert_ne!(ecdsa_wallet.get_address()[..1], test_wallet.get_address()[..1]);
+
+        let ecdsa_coinbase =
+            Transaction::new_coin_base_tx(ecdsa_wallet.get_address(), "a".into()).unwrap();
+        let test_coinbase =
+            Transaction::new_coin_base_tx(test_wallet.get_address(), "b".into()).unwrap();
+        assert_eq!(test_coinbase.vout[0].scheme, SchemeTag::Test);
+
+        // 两种算法的输出互相转账
+        let to_test = vec![TxOutput::new_tx_output(50, &test_wallet.address())];
+        let to_ecdsa = vec![TxOutput::new_tx_output(50, &ecdsa_wallet.address())];
+        let tx1 = testutil::spend(&ecdsa_wallet, &ecdsa_coinbase, 0, to_test);
+        let tx2 = testutil::spend(&test_wallet, &test_coinbase, 0, to_ecdsa);
+        assert_eq!(tx1.vout[0].scheme, SchemeTag::Test);
+        assert_eq!(tx2.vout[0].scheme, SchemeTag::P256Ecdsa);
+
+        let prev_txs = HashMap::from([
+            (ecdsa_coinbase.id.clone(), ecdsa_coinbase.clone()),
+            (test_coinbase.id.clone(), test_coinbase.clone()),
+        ]);
+        let block = Block::new_block("".into(), 1, vec![tx1.clone(), tx2.clone()]).unwrap();
+        for tx in block.transactions.iter() {
+            assert!(tx.verify(prev_txs.clone(), 1).unwrap());
+        }
+
+        // 用另一种算法的签名冒充不能通过验证
+        let mut forged = tx2.clone();
+        forged.vin[0].pubkey = ecdsa_wallet.public_key.clone();
+        assert!(!forged.verify(prev_txs, 1).unwrap());
+    }
+
+    #[test]
+    fn test_htlc_claim_and_refund() {
+        let receiver = Wallet::new_wallet();
+        let refunder = Wallet::new_wallet();
+        let preimage = b"secret".to_vec();
+
+        let mut funding = Transaction {
+            id: String::new(),
+            vin: vec![],
+            vout: vec![TxOutput::new_htlc_output(
+                30,
+                &receiver.address(),
+                &refunder.address(),
+                sha256::digest(preimage.as_slice()).to_uppercase(),
+                10,
+            )
+            .unwrap()],
+        };
+        funding.set_id().unwrap();
+        let prev_txs = HashMap::from([(funding.id.clone(), funding.clone())]);
+
+        let spend = |wallet: &Wallet, preimage: Option<&[u8]>| {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TxInput {
+                    txid: funding.id.clone(),
+                    vout: 0,
+                    pubkey: wallet.public_key.clone(),
+                    preimage: preimage.map(hex::encode).unwrap_or_default(),
+                    ..Default::default()
+                }],
+                vout: vec![TxOutput::new_tx_output(30, &wallet.address())],
+            };
+            tx.set_id().unwrap();
+            tx.sign(&wallet.secret_key, prev_txs.clone()).unwrap();
+            tx
+        };
+
+        let claim = spend(&receiver, Some(&preimage));
+        assert!(claim.verify(prev_txs.clone(), 1).unwrap());
+        assert!(!spend(&receiver, Some(b"wrong")).verify(prev_txs.clone(), 1).unwrap());
+        assert!(!spend(&refunder, Some(&preimage)).verify(prev_txs.clone(), 1).unwrap());
+
+        let refund = spend(&refunder, None);
+        assert!(!refund.verify(prev_txs.clone(), 9).unwrap());
+        assert!(refund.verify(prev_txs.clone(), 10).unwrap());
+        assert!(!spend(&receiver, None).verify(prev_txs, 10).unwrap());
+    }
+
+    #[test]
+    fn test_bumpfee_keeps_original_inputs() {
+        let path = std::env::temp_dir().join(format!("btc-bumpfee-test-{}", std::process::id()));
+        let datadir = DataDir::new(&path).unwrap();
+        let mut wallets = Wallets::new_wallets(&datadir).unwrap();
+        let first: Address = wallets
+            .create_wallet(AddressEncoding::Base58)
+            .unwrap()
+            .parse()
+            .unwrap();
+        let sender: Address = wallets
+            .create_wallet(AddressEncoding::Base58)
+            .unwrap()
+            .parse()
+            .unwrap();
+        wallets.save_to_file().unwrap();
+        let recipient = Wallet::new_wallet().address();
+        let mut bc = Blockchain::create_block_chain(first.to_string(), &datadir).unwrap();
+        let funding =
+            Transaction::new_utxo_transaction(&first, &sender, 20, 0, false, &bc).unwrap();
+        bc.mine_block(vec![funding.clone()]).unwrap();
+
+        let orig = Transaction::new_utxo_transaction(&sender, &recipient, 5, 1, true, &bc).unwrap();
+        Mempool::new(bc.clone()).add(orig.clone()).unwrap();
+        // 发送之后、提高手续费之前，发送方又收到一个已确认的输出
+        let change = Wallets::new_wallets(&datadir)
+            .unwrap()
+            .output_address(&funding.vout[1])
+            .unwrap();
+        let payment =
+            Transaction::new_utxo_transaction(&change, &sender, 10, 0, false, &bc).unwrap();
+        bc.mine_block(vec![payment]).unwrap();
+        let mempool = Mempool::new(bc.clone());
+
+        let spends_orig = |tx: &Transaction| {
+            tx.vin
+                .iter()
+                .any(|v| v.txid == orig.vin[0].txid && v.vout == orig.vin[0].vout)
+        };
+        // 找零足够时只减少找零
+        let bumped = Transaction::new_bumpfee_transaction(&orig.id, Some(3), &bc).unwrap();
+        assert_eq!(bumped.vin.len(), 1);
+        assert!(spends_orig(&bumped));
+        mempool.add(bumped.clone()).unwrap();
+        assert_eq!(mempool.transactions().unwrap().len(), 1);
+        assert_eq!(mempool.fee(&bumped).unwrap(), 3);
+
+        // 找零不够时补充新的输入，原交易的输入仍然保留
+        let bumped_again = Transaction::new_bumpfee_transaction(&bumped.id, Some(20), &bc).unwrap();
+        assert_eq!(bumped_again.vin.len(), 2);
+        assert!(spends_orig(&bumped_again));
+        mempool.add(bumped_again.clone()).unwrap();
+        let pending = mempool.transactions().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, bumped_again.id);
+        let paid: isize = bumped_again
+            .vout
+            .iter()
+            .filter(|out| out.pubkey_hash == recipient.pubkey_hash())
+            .map(|out| out.value)
+            .sum();
+        assert_eq!(paid, 5);
+        assert_eq!(mempool.fee(&bumped_again).unwrap(), 20);
+        drop(bc);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -1,15 +1,66 @@
-use std::collections::HashMap;
-use std::str::from_utf8;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
 
-use crate::block::Block;
+use crate::block::{Block, BlockHeader};
 use crate::blockchain::Blockchain;
-use crate::transaction::TxOutput;
-use anyhow::anyhow;
-use anyhow::Result;
-use serde::Serialize;
-use sled::{transaction::ConflictableTransactionError, Transactional};
+use crate::params::ChainParams;
+use crate::proof_of_work;
+use crate::store::{Batch, CHAINSTATE, UNDO};
+use crate::transaction::{Transaction, TxOutput};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+// 一个未花费的输出，key 为 (txid, vout)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Coin {
+    pub output: TxOutput,
+    pub height: u64, // 所在区块的高度
+    pub coinbase: bool,
+}
+
+impl Coin {
+    pub fn new(output: TxOutput, height: u64, coinbase: bool) -> Self {
+        Self {
+            output,
+            height,
+            coinbase,
+        }
+    }
+}
+
+// 区块花费掉的输出，回滚区块时用来恢复 UTXO 集合
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    pub spent: Vec<UtxoEntry>,
+}
+
+impl BlockUndo {
+    // 用花费掉的输出拼出被引用的交易，只有被花费的位置有效
+    pub fn prev_transactions(&self) -> HashMap<String, Transaction> {
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        for spent in self.spent.iter() {
+            let prev_tx = prev_txs.entry(spent.txid.clone()).or_insert(Transaction {
+                id: spent.txid.clone(),
+                ..Default::default()
+            });
+            if prev_tx.vout.len() <= spent.vout as usize {
+                prev_tx.vout.resize(spent.vout as usize + 1, TxOutput::default());
+            }
+            prev_tx.vout[spent.vout as usize] = spent.coin.output.clone();
+        }
+        prev_txs
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoEntry {
+    pub txid: String,
+    pub vout: isize,
+    pub coin: Coin,
+}
 
-const UTXO_BUCKET: &str = "chainstate";
 pub struct UTXOSet {
     bc: Blockchain,
 }
@@ -19,26 +70,97 @@ impl UTXOSet {
         Self { bc }
     }
 
+    // 从创世块开始重放所有区块，同时重建每个区块的回滚数据
     pub fn reindex(&self) -> Result<()> {
-        let db: sled::Db = self.bc.get_db();
-        db.drop_tree(UTXO_BUCKET)?;
+        let mut coins: HashMap<(String, isize), Coin> = HashMap::new();
 
-        let utxo = self.bc.find_utxo()?;
+        let mut batch = Batch::default();
+        batch.clear(CHAINSTATE);
+        batch.clear(UNDO);
+        replay(&mut coins, self.bc.blocks_from_genesis()?, &mut batch)?;
+        for ((txid, vout), coin) in coins.iter() {
+            batch.put_utxo(txid, *vout, coin)?;
+        }
 
-        let bucket = db.open_tree(UTXO_BUCKET)?;
-        let r = bucket.transaction(|tx_db| {
-            for (tx_id, outs) in utxo.iter() {
-                let value = serde_json::to_string(&outs)
-                    .map_err(|e| ConflictableTransactionError::Abort(anyhow!(e)))?;
-                tx_db.insert(tx_id.as_bytes(), value.as_bytes())?;
-            }
+        self.bc.get_store().apply(batch)
+    }
+
+    // 当前链上 height 处的 UTXO 集合，按 (txid, vout) 排序
+    pub fn dump(&self, height: u64) -> Result<UtxoSnapshot> {
+        let block = self.bc.find_block(&height.to_string())?;
+        let mut coins: Vec<UtxoEntry> = self
+            .bc
+            .find_utxo_at(&block.hash)?
+            .into_iter()
+            .map(|((txid, vout), coin)| UtxoEntry { txid, vout, coin })
+            .collect();
+        coins.sort_by(|a, b| (&a.txid, a.vout).cmp(&(&b.txid, b.vout)));
+
+        Ok(UtxoSnapshot {
+            height,
+            block_hash: block.hash,
+            utxo_hash: utxo_hash(&coins)?,
+            coins,
+            headers: self.bc.headers_in_range(None, Some(height))?,
+        })
+    }
+
+    // 用快照替换 UTXO 集合，再重放快照之后的区块，快照必须和链参数中固定的hash一致；
+    // 没有区块数据的节点使用 Blockchain::create_from_snapshot
+    pub fn load(&self, snapshot: &UtxoSnapshot, trust_config: bool) -> Result<()> {
+        let genesis = self.bc.find_block("0")?;
+        snapshot.check(&ChainParams::new(&genesis.hash, self.bc.get_config(), trust_config))?;
+        let block = self.bc.find_block(&snapshot.block_hash)?;
+        if block.height != snapshot.height {
+            return Err(anyhow!("Snapshot block {} has a different height", block.hash));
+        }
+
+        let mut coins: HashMap<(String, isize), Coin> = snapshot
+            .coins
+            .iter()
+            .map(|e| ((e.txid.clone(), e.vout), e.coin.clone()))
+            .collect();
+        // 快照之前的区块可以已经被裁剪
+        let blocks = self.bc.blocks_in_range(Some(snapshot.height + 1), None)?;
+
+        let mut batch = Batch::default();
+        batch.clear(CHAINSTATE);
+        replay(&mut coins, blocks, &mut batch)?;
+        for ((txid, vout), coin) in coins.iter() {
+            batch.put_utxo(txid, *vout, coin)?;
+        }
 
-            Ok(())
-        });
+        self.bc.get_store().apply(batch)
+    }
 
-        match r {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(e)),
+    // 旧版本按 txid 保存输出数组，花费后下标会错位，无法直接转换，只能从区块重建
+    pub fn migrate(&self) -> Result<bool> {
+        let legacy = self
+            .bc
+            .get_store()
+            .scan(CHAINSTATE)?
+            .iter()
+            .any(|(key, _)| !key.contains(&b':'));
+        if legacy {
+            info!("Rebuilding chainstate keyed by outpoint");
+            self.reindex()?;
+        }
+        Ok(legacy)
+    }
+
+    // 和从区块重新计算的结果比较，返回第一个不一致的输出
+    pub fn check_consistency(&self) -> Result<()> {
+        let mut expected = self.bc.find_utxo()?;
+        for ((txid, vout), coin) in self.bc.get_store().utxos()? {
+            match expected.remove(&(txid.clone(), vout)) {
+                Some(c) if c == coin => {}
+                Some(_) => return Err(anyhow!("UTXO {txid}:{vout} differs from the blocks")),
+                None => return Err(anyhow!("UTXO {txid}:{vout} is spent or does not exist")),
+            }
+        }
+        match expected.keys().min() {
+            Some((txid, vout)) => Err(anyhow!("UTXO {txid}:{vout} is missing from chainstate")),
+            None => Ok(()),
         }
     }
 
@@ -46,27 +168,19 @@ impl UTXOSet {
         &self,
         pubkey_hash: &str,
         amount: isize,
-        // isize：余额， map：<String：address， Vec：index of txoutput>
+        exclude: &HashSet<(String, isize)>, // 不能选择的输出，例如已经被交易池中的交易花费
+        // isize：余额， map：<String：txid， Vec：index of txoutput>
     ) -> Result<(isize, HashMap<String, Vec<isize>>)> {
         let mut unspent_outputs = HashMap::<String, Vec<isize>>::new();
         let mut accumulated = 0;
 
-        let bucket = self.bc.get_db().open_tree(UTXO_BUCKET)?;
-        for r in bucket.iter() {
-            match r {
-                Ok((key, value)) => {
-                    let tx_id = from_utf8(key.as_ref())?;
-                    let outs: Vec<TxOutput> = serde_json::from_slice(value.as_ref())?;
-
-                    for (index, out) in outs.iter().enumerate() {
-                        if out.is_locked_with_key(pubkey_hash) && accumulated < amount {
-                            accumulated += out.value;
-                            let out_idx = unspent_outputs.entry(tx_id.into()).or_insert(vec![]);
-                            out_idx.push(index as isize);
-                        }
-                    }
-                }
-                Err(e) => return Err(anyhow!(e)),
+        for ((txid, vout), coin) in self.bc.get_store().utxos()? {
+            if exclude.contains(&(txid.clone(), vout)) {
+                continue;
+            }
+            if coin.output.is_locked_with_key(pubkey_hash) && accumulated < amount {
+                accumulated += coin.output.value;
+                unspent_outputs.entry(txid).or_default().push(vout);
             }
         }
 
@@ -76,67 +190,362 @@ impl UTXOSet {
     pub fn find_utxo(&self, pubkey_hash: &str) -> Result<Vec<TxOutput>> {
         let mut outputs = Vec::new();
 
-        let bucket = self.bc.get_db().open_tree(UTXO_BUCKET)?;
-        for r in bucket.iter() {
-            match r {
-                Ok((_, value)) => {
-                    let outs: Vec<TxOutput> = serde_json::from_slice(value.as_ref())?;
+        for (_, coin) in self.bc.get_store().utxos()? {
+            if coin.output.is_locked_with_key(pubkey_hash) {
+                outputs.push(coin.output);
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    pub fn disconnect(&self, block: &Block) -> Result<()> {
+        let mut batch = Batch::default();
+        self.disconnect_block(&mut batch, block)?;
+        self.bc.get_store().apply(batch)
+    }
+
+    // 把区块对 UTXO 集合的修改和它的回滚数据写入 batch
+    pub fn connect_block(&self, batch: &mut Batch, block: &Block) -> Result<()> {
+        let store = self.bc.get_store();
+        // 同一个区块中可能花费本区块前面交易的输出，先在内存中合并修改
+        let mut changed: HashMap<(String, isize), Option<Coin>> = HashMap::new();
+        let mut undo = BlockUndo::default();
 
-                    for out in outs.into_iter() {
-                        if out.is_locked_with_key(pubkey_hash) {
-                            outputs.push(out);
-                        }
+        for tx in block.transactions.iter() {
+            if !tx.is_coinbase() {
+                for vin in tx.vin.iter() {
+                    let outpoint = (vin.txid.clone(), vin.vout);
+                    let coin = match changed.get(&outpoint) {
+                        Some(coin) => coin.clone(),
+                        None => store.get_utxo(&vin.txid, vin.vout)?,
                     }
+                    .ok_or(anyhow!("UTXO {}:{} not found", vin.txid, vin.vout))?;
+                    undo.spent.push(UtxoEntry {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                        coin,
+                    });
+                    changed.insert(outpoint, None);
                 }
-                Err(e) => return Err(anyhow!(e)),
+            }
+
+            for (index, out) in tx.vout.iter().enumerate() {
+                let coin = Coin::new(out.clone(), block.get_height(), tx.is_coinbase());
+                changed.insert((tx.id.clone(), index as isize), Some(coin));
             }
         }
 
-        Ok(outputs)
+        for ((txid, vout), coin) in changed.iter() {
+            match coin {
+                Some(coin) => batch.put_utxo(txid, *vout, coin)?,
+                None => batch.remove_utxo(txid, *vout),
+            }
+        }
+        batch.insert(UNDO, block.get_hash(), serde_json::to_string(&undo)?);
+        Ok(())
+    }
+
+    pub fn get_undo(&self, hash: &str) -> Result<Option<BlockUndo>> {
+        match self.bc.get_store().get(UNDO, hash.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // 恢复区块花费的输出并删除它产生的输出，需要在更新的区块之后按顺序调用
+    pub fn disconnect_block(&self, batch: &mut Batch, block: &Block) -> Result<()> {
+        let undo = self.get_undo(&block.get_hash())?.ok_or(anyhow!(
+            "No undo data for block {}, run reindex first",
+            block.get_hash()
+        ))?;
+
+        // 先恢复再删除，同一个区块中产生又被花费的输出最终会被删除
+        for spent in undo.spent.iter() {
+            batch.put_utxo(&spent.txid, spent.vout, &spent.coin)?;
+        }
+        for tx in block.transactions.iter() {
+            for index in 0..tx.vout.len() {
+                batch.remove_utxo(&tx.id, index as isize);
+            }
+        }
+        batch.remove(UNDO, block.get_hash());
+        Ok(())
     }
+}
 
-    pub fn update(&self, block: Block) -> Result<()> {
-        let db = self.bc.get_db();
-        let bucket = db.open_tree(UTXO_BUCKET)?;
-
-        let r = bucket.transaction(|tx_db| {
-            for tx in block.transactions.iter() {
-                if !tx.is_coinbase() {
-                    for vin in tx.vin.iter() {
-                        let oiv = tx_db.get(vin.txid.clone())?;
-                        if let Some(iv) = oiv {
-                            let outs: Vec<TxOutput> = serde_json::from_slice(iv.as_ref())
-                                .map_err(|e| ConflictableTransactionError::Abort(anyhow!(e)))?;
-
-                            let new_outs: Vec<TxOutput> = outs
-                                .into_iter()
-                                .enumerate()
-                                .filter(|(index, _)| *index != vin.vout as usize)
-                                .map(|(_, v)| v)
-                                .collect();
-
-                            if new_outs.is_empty() {
-                                tx_db.remove(vin.txid.as_bytes())?;
-                            } else {
-                                let value = serde_json::to_string(&new_outs)
-                                    .map_err(|e| ConflictableTransactionError::Abort(anyhow!(e)))?;
-                                tx_db.insert(vin.txid.as_bytes(), value.as_bytes())?;
-                            }
-                        }
+// 按顺序把区块应用到内存中的 UTXO 集合，并把每个区块的回滚数据写入 batch
+fn replay(
+    coins: &mut HashMap<(String, isize), Coin>,
+    blocks: impl Iterator<Item = Result<Block>>,
+    batch: &mut Batch,
+) -> Result<()> {
+    for block in blocks {
+        let block = block?;
+        let mut undo = BlockUndo::default();
+        for tx in block.transactions.iter() {
+            if !tx.is_coinbase() {
+                for vin in tx.vin.iter() {
+                    // 旧版本没有检查双花，链上可能有花费不存在的输出的交易
+                    if let Some(coin) = coins.remove(&(vin.txid.clone(), vin.vout)) {
+                        undo.spent.push(UtxoEntry {
+                            txid: vin.txid.clone(),
+                            vout: vin.vout,
+                            coin,
+                        });
                     }
                 }
+            }
+            for (index, out) in tx.vout.iter().enumerate() {
+                let coin = Coin::new(out.clone(), block.get_height(), tx.is_coinbase());
+                coins.insert((tx.id.clone(), index as isize), coin);
+            }
+        }
+        batch.insert(UNDO, block.get_hash(), serde_json::to_string(&undo)?);
+    }
+    Ok(())
+}
+
+// UTXO 集合的承诺hash，coins 的顺序会影响结果
+pub fn utxo_hash(coins: &[UtxoEntry]) -> Result<String> {
+    let mut data = vec![];
+    for entry in coins {
+        data.extend(serde_json::to_vec(entry)?);
+        data.push(b'\n');
+    }
+    Ok(sha256::digest(data))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoSnapshot {
+    pub height: u64,
+    pub block_hash: String,
+    pub utxo_hash: String,
+    pub coins: Vec<UtxoEntry>,
+    // 创世块到快照区块的区块头，没有区块数据的节点从这里开始
+    #[serde(default)]
+    pub headers: Vec<BlockHeader>,
+}
+
+impl UtxoSnapshot {
+    // 快照必须和链参数中固定的hash一致，内容和hash一致
+    pub fn check(&self, params: &ChainParams) -> Result<()> {
+        let pinned = params
+            .assume_utxo_at(self.height)
+            .ok_or(anyhow!("No pinned UTXO hash at height {}", self.height))?;
+        if pinned.block_hash != self.block_hash || pinned.utxo_hash != self.utxo_hash {
+            return Err(anyhow!(
+                "Snapshot does not match the pinned hash at height {}",
+                self.height
+            ));
+        }
+        if utxo_hash(&self.coins)? != self.utxo_hash {
+            return Err(anyhow!("Snapshot contents do not match its hash"));
+        }
+        Ok(())
+    }
 
-                let value = serde_json::to_string(&tx.vout)
-                    .map_err(|e| ConflictableTransactionError::Abort(anyhow!(e)))?;
-                tx_db.insert(tx.id.as_bytes(), value.as_bytes())?;
+    // 区块头从创世块开始依次相连，满足工作量证明，并且结束于快照的区块
+    pub fn check_headers(&self) -> Result<()> {
+        let mut prev_hash = String::new();
+        for (height, header) in self.headers.iter().enumerate() {
+            if header.height != height as u64 || header.prev_block_hash != prev_hash {
+                return Err(anyhow!("Snapshot header {} is not linked", header.hash));
             }
+            if !proof_of_work::validate_header(header) {
+                return Err(anyhow!("Snapshot header {} has invalid proof of work", header.hash));
+            }
+            prev_hash = header.hash.clone();
+        }
+        if prev_hash != self.block_hash || self.headers.len() as u64 != self.height + 1 {
+            return Err(anyhow!("Snapshot headers do not end at block {}", self.block_hash));
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
 
-            Ok(())
-        });
+    pub fn load_from_file(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{
+        blockchain::Blockchain,
+        config::Config,
+        params::AssumeUtxo,
+        store::{Batch, ChainStore, MemoryStore, CHAINSTATE},
+        testutil::{new_chain, spend_to_self},
+        wallet::hash_pubkey,
+    };
+
+    use super::UTXOSet;
+
+    #[test]
+    fn test_spend_keeps_output_indexes() {
+        let (wallet, store, mut bc, coinbase) = new_chain(Config::default());
+        let pubkey_hash = hex::encode(hash_pubkey(&wallet.public_key));
+        let utxoset = UTXOSet::new(bc.clone());
+
+        let split = spend_to_self(&wallet, &coinbase, 0, vec![10, 40]);
+        bc.mine_block(vec![split.clone()]).unwrap();
+        let first = spend_to_self(&wallet, &split, 0, vec![10]);
+        bc.mine_block(vec![first]).unwrap();
+
+        // 花费 vout 0 之后，vout 1 仍然可以用原来的下标花费
+        let (_, outputs) = utxoset
+            .find_spentable_outputs(&pubkey_hash, 50, &Default::default())
+            .unwrap();
+        assert_eq!(outputs[&split.id], vec![1]);
+        let coin = store.get_utxo(&split.id, 1).unwrap().unwrap();
+        assert_eq!((coin.output.value, coin.height, coin.coinbase), (40, 1, false));
+        let second = spend_to_self(&wallet, &split, 1, vec![40]);
+        bc.mine_block(vec![second]).unwrap();
+        // UTXOSet 持有的链停留在创建时的 tip，检查前需要换成最新的
+        let utxoset = UTXOSet::new(bc.clone());
+        utxoset.check_consistency().unwrap();
+
+        // 旧的按 txid 保存的数据会被重建
+        let mut batch = Batch::default();
+        batch.insert(CHAINSTATE, &split.id, "[]");
+        store.apply(batch).unwrap();
+        assert!(utxoset.check_consistency().is_err());
+        assert!(utxoset.migrate().unwrap());
+        assert!(!utxoset.migrate().unwrap());
+        utxoset.check_consistency().unwrap();
+    }
+
+    #[test]
+    fn test_rollback_restores_chainstate() {
+        let config = Config {
+            txindex: true,
+            addrindex: true,
+            ..Default::default()
+        };
+        let (wallet, store, mut bc, coinbase) = new_chain(config);
+        let pubkey_hash = hex::encode(hash_pubkey(&wallet.public_key));
+
+        let split = spend_to_self(&wallet, &coinbase, 0, vec![10, 40]);
+        bc.mine_block(vec![split.clone()]).unwrap();
+        let chainstate = store.scan(CHAINSTATE).unwrap();
+        let history = bc.address_history(&pubkey_hash).unwrap();
+
+        let merged = spend_to_self(&wallet, &split, 1, vec![40]);
+        bc.mine_block(vec![merged.clone()]).unwrap();
 
-        match r {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(e)),
+        let blocks = bc.rollback("1").unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        assert_eq!(store.scan(CHAINSTATE).unwrap(), chainstate);
+        assert_eq!(bc.address_history(&pubkey_hash).unwrap(), history);
+        assert!(bc.find_transaction(&merged.id).is_err());
+        UTXOSet::new(bc.clone()).check_consistency().unwrap();
+
+        // 区块数据还在，可以重新导入撤销回滚
+        assert!(store.get_block(&blocks[0].get_hash()).unwrap().is_some());
+        bc.import_block(&blocks[0]).unwrap();
+        assert_eq!(bc.find_transaction(&merged.id).unwrap().id, merged.id);
+        UTXOSet::new(bc.clone()).check_consistency().unwrap();
+
+        // 回滚到创世块
+        bc.rollback(&bc.find_block("0").unwrap().hash).unwrap();
+        assert_eq!(bc.address_history(&pubkey_hash).unwrap().len(), 1);
+        UTXOSet::new(bc.clone()).check_consistency().unwrap();
+        assert!(bc.rollback("5").is_err());
+    }
+
+    #[test]
+    fn test_load_pinned_snapshot() {
+        let (wallet, store, mut bc, coinbase) = new_chain(Config::default());
+        let split = spend_to_self(&wallet, &coinbase, 0, vec![10, 40]);
+        bc.mine_block(vec![split.clone()]).unwrap();
+        let merged = spend_to_self(&wallet, &split, 1, vec![40]);
+        bc.mine_block(vec![merged]).unwrap();
+
+        let snapshot = UTXOSet::new(bc.clone()).dump(1).unwrap();
+        assert_eq!(snapshot.coins.len(), 2);
+        assert_eq!(UTXOSet::new(bc.clone()).dump(1).unwrap().utxo_hash, snapshot.utxo_hash);
+        assert!(UTXOSet::new(bc.clone()).load(&snapshot, true).is_err());
+
+        let config = Config {
+            assume_utxo: vec![AssumeUtxo {
+                height: 1,
+                block_hash: snapshot.block_hash.clone(),
+                utxo_hash: snapshot.utxo_hash.clone(),
+            }],
+            ..Default::default()
+        };
+        let utxoset = UTXOSet::new(Blockchain::open(store.clone(), config.clone()).unwrap());
+        let mut tampered = snapshot.clone();
+        tampered.coins[0].coin.output.value += 1;
+        assert!(utxoset.load(&tampered, true).is_err());
+        // 没有 --trust-config 时不使用 config.json 中的值
+        assert!(utxoset.load(&snapshot, false).is_err());
+
+        let mut batch = Batch::default();
+        batch.clear(CHAINSTATE);
+        store.apply(batch).unwrap();
+        utxoset.load(&snapshot, true).unwrap();
+        utxoset.check_consistency().unwrap();
+
+        // 没有区块数据的节点只用快照中的区块头开始，再接入之后的区块
+        let empty = Arc::new(MemoryStore::new());
+        let mut bad_headers = snapshot.clone();
+        bad_headers.headers[1].nonce += 1;
+        let err =
+            Blockchain::create_from_snapshot(empty.clone(), &bad_headers, config.clone(), true);
+        assert!(err.unwrap_err().to_string().contains("proof of work"));
+        let mut fresh =
+            Blockchain::create_from_snapshot(empty.clone(), &snapshot, config, true).unwrap();
+        assert_eq!(fresh.tip, snapshot.block_hash);
+        assert_eq!(empty.get_prune_height().unwrap(), Some(1));
+        fresh.import_block(&bc.iterator().next().unwrap().unwrap()).unwrap();
+        assert_eq!(fresh.tip, bc.tip);
+        assert_eq!(empty.utxos().unwrap(), store.utxos().unwrap());
+    }
+
+    #[test]
+    fn test_pruned_chain() {
+        let config = Config {
+            prune: Some(6),
+            ..Default::default()
+        };
+        // 太小的裁剪深度在打开链时就被拒绝
+        let shallow = Config {
+            prune: Some(5),
+            ..Default::default()
+        };
+        let (wallet, store, mut bc, coinbase) = new_chain(config);
+        let empty = Arc::new(MemoryStore::new());
+        assert!(Blockchain::create(empty, wallet.get_address(), shallow).is_err());
+        let split = spend_to_self(&wallet, &coinbase, 0, vec![10, 40]);
+        bc.mine_block(vec![split.clone()]).unwrap();
+        let mut prev = split.clone();
+        for _ in 2..=7 {
+            prev = spend_to_self(&wallet, &prev, 0, vec![10]);
+            bc.mine_block(vec![prev.clone()]).unwrap();
         }
+
+        assert_eq!(store.get_prune_height().unwrap(), Some(1));
+        assert!(store.is_pruned(&bc.find_block("1").unwrap().hash).unwrap());
+        assert!(!store.is_pruned(&bc.find_block("2").unwrap().hash).unwrap());
+        let err = bc.find_transaction(&split.id).unwrap_err();
+        assert!(err.to_string().contains("unpruned"));
+
+        // 被裁剪区块中未花费的输出仍然可以花费
+        let tx = spend_to_self(&wallet, &split, 1, vec![40]);
+        bc.mine_block(vec![tx]).unwrap();
+        assert_eq!(store.get_prune_height().unwrap(), Some(2));
+
+        assert!(UTXOSet::new(bc.clone()).reindex().is_err());
+        assert!(bc.rollback("2").is_err());
+        bc.rollback("3").unwrap();
     }
 }
@@ -0,0 +1,214 @@
+use std::str::from_utf8;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    block::{Block, BlockHeader},
+    blockchain::{verify_transactions, Blockchain},
+    proof_of_work::validate_header,
+    store::{BLOCKS, HEADERS},
+    utxoset::UTXOSet,
+};
+
+// 检查的结果，发现的第一个问题通过错误返回
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub blocks: usize,
+    pub pruned: usize,
+    pub utxo_checked: bool,
+}
+
+enum ChainEntry {
+    Full(Block),
+    Pruned(BlockHeader),
+}
+
+impl ChainEntry {
+    fn header(&self) -> Result<BlockHeader> {
+        match self {
+            ChainEntry::Full(block) => block.header(),
+            ChainEntry::Pruned(header) => Ok(header.clone()),
+        }
+    }
+}
+
+// level 1: 所有区块都可以从 tip 到达并且可以反序列化
+// level 2: 工作量证明、父区块hash和高度
+// level 3: 所有交易的签名
+// level 4: 从区块重新计算 UTXO 集合并和 chainstate 比较
+pub fn verify_chain(bc: &Blockchain, level: u8) -> Result<VerifyReport> {
+    let entries = load_entries(bc)?;
+    let mut report = VerifyReport {
+        blocks: entries.len(),
+        pruned: entries
+            .iter()
+            .filter(|e| matches!(e, ChainEntry::Pruned(_)))
+            .count(),
+        ..Default::default()
+    };
+    println!(
+        "Level 1: {} blocks reachable, {} pruned",
+        report.blocks, report.pruned
+    );
+
+    if level >= 2 {
+        let mut prev: Option<BlockHeader> = None;
+        for entry in entries.iter() {
+            let header = entry.header()?;
+            check_header(&header, prev.as_ref())?;
+            prev = Some(header);
+        }
+        println!("Level 2: proof of work and linkage ok");
+    }
+
+    let utxoset = UTXOSet::new(bc.clone());
+    if level >= 3 {
+        for entry in entries.iter() {
+            let ChainEntry::Full(block) = entry else {
+                continue;
+            };
+            let fail = |problem: String| {
+                anyhow!("Block {} at height {}: {problem}", block.hash, block.height)
+            };
+            if block.height > 0 && block.transactions.iter().any(|tx| tx.is_coinbase()) {
+                return Err(fail("contains a coinbase transaction".into()));
+            }
+            // 裁剪过的链上被引用的交易可能已经删除，使用回滚数据中保存的输出
+            let verified = if report.pruned > 0 {
+                match utxoset.get_undo(&block.hash)? {
+                    Some(undo) => verify_transactions(
+                        &block.transactions,
+                        &undo.prev_transactions(),
+                        block.height,
+                        bc.get_sigcache(),
+                    ),
+                    None => return Err(fail("undo data is missing".into())),
+                }
+            } else {
+                bc.verify_block_transactions(&block.transactions, block.height)
+            };
+            match verified {
+                Ok(true) => {}
+                Ok(false) => return Err(fail("transaction verification failed".into())),
+                Err(e) => return Err(fail(e.to_string())),
+            }
+        }
+        println!("Level 3: signatures ok");
+    }
+
+    if level >= 4 {
+        if report.pruned > 0 {
+            println!("Level 4: skipped, the chain is pruned");
+        } else {
+            utxoset
+                .check_consistency()
+                .map_err(|e| anyhow!("Chainstate: {e}"))?;
+            report.utxo_checked = true;
+            println!("Level 4: chainstate matches the blocks");
+        }
+    }
+
+    Ok(report)
+}
+
+// 从 tip 开始直接读取存储中的数据，返回从创世块开始的区块
+fn load_entries(bc: &Blockchain) -> Result<Vec<ChainEntry>> {
+    let store = bc.get_store();
+    let mut entries = vec![];
+    let mut hash = bc.tip.clone();
+    let mut child: Option<u64> = None;
+    loop {
+        let location = match child {
+            Some(height) => format!("Block {hash}, parent of height {height}"),
+            None => format!("Tip block {hash}"),
+        };
+        let entry = match store.get(BLOCKS, hash.as_bytes())? {
+            Some(data) => {
+                let block = from_utf8(&data)
+                    .map_err(anyhow::Error::from)
+                    .and_then(Block::deserialize)
+                    .map_err(|e| anyhow!("{location} does not deserialize: {e}"))?;
+                ChainEntry::Full(block)
+            }
+            None => match store.get(HEADERS, hash.as_bytes())? {
+                Some(data) => ChainEntry::Pruned(
+                    serde_json::from_slice(&data)
+                        .map_err(|e| anyhow!("{location} has a corrupted header: {e}"))?,
+                ),
+                None => return Err(anyhow!("{location} is missing")),
+            },
+        };
+
+        let header = entry.header()?;
+        if header.hash != hash {
+            return Err(anyhow!("{location} is stored with hash {}", header.hash));
+        }
+        entries.push(entry);
+        if header.prev_block_hash.is_empty() {
+            break;
+        }
+        hash = header.prev_block_hash;
+        child = Some(header.height);
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+fn check_header(header: &BlockHeader, prev: Option<&BlockHeader>) -> Result<()> {
+    let fail = |problem: &str| {
+        anyhow!(
+            "Block {} at height {}: {problem}",
+            header.hash,
+            header.height
+        )
+    };
+    match prev {
+        None if header.height != 0 => return Err(fail("genesis block must have height 0")),
+        Some(prev) if header.height != prev.height + 1 => {
+            return Err(fail(&format!(
+                "height does not follow parent height {}",
+                prev.height
+            )))
+        }
+        _ => {}
+    }
+    if !validate_header(header) {
+        return Err(fail("invalid proof of work"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::Config,
+        store::{Batch, ChainStore, BLOCKS},
+        testutil::{new_chain, spend_to_self},
+    };
+
+    use super::verify_chain;
+
+    #[test]
+    fn test_verify_chain_reports_corruption() {
+        let (wallet, store, mut bc, coinbase) = new_chain(Config::default());
+        let tx = spend_to_self(&wallet, &coinbase, 0, vec![50]);
+        let block = bc.mine_block(vec![tx]).unwrap();
+        assert!(verify_chain(&bc, 4).unwrap().utxo_checked);
+
+        // 修改交易金额后工作量证明不再成立
+        let mut tampered = block.clone();
+        tampered.transactions[0].vout[0].value = 49;
+        let mut batch = Batch::default();
+        batch.put_block(&tampered).unwrap();
+        store.apply(batch).unwrap();
+        assert!(verify_chain(&bc, 1).is_ok());
+        let err = verify_chain(&bc, 2).unwrap_err().to_string();
+        assert!(err.contains("height 1: invalid proof of work"), "{err}");
+
+        let mut batch = Batch::default();
+        batch.insert(BLOCKS, block.get_hash(), "garbage");
+        store.apply(batch).unwrap();
+        let err = verify_chain(&bc, 1).unwrap_err().to_string();
+        assert!(err.contains("does not deserialize"), "{err}");
+    }
+}
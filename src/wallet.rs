@@ -1,39 +1,148 @@
 use std::{
-    collections::HashMap,
-    fs::OpenOptions,
-    hash::Hasher,
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
     io::{self, Read, Write},
-    str::from_utf8,
+    path::PathBuf,
 };
 
 use anyhow::anyhow;
-use base58::{FromBase58, ToBase58};
-use ecdsa::{
-    elliptic_curve::{PublicKey, SecretKey},
-    signature::rand_core::OsRng,
-    SignatureEncoding, VerifyingKey,
-};
-use p256::{
-    ecdsa::{signature::Signer, SigningKey},
-    NistP256,
-};
+use bip39::{Language, Mnemonic};
+use ecdsa::signature::rand_core::{OsRng, RngCore};
 
 use ripemd::Ripemd160;
+
+use crate::{
+    address::{Address, AddressEncoding},
+    agent,
+    blockchain::{output_pubkey_hashes, Blockchain},
+    crypter::{self, MasterKey, Sealed, KEY_LEN},
+    datadir::DataDir,
+    hd::ExtendedKey,
+    mempool::Mempool,
+    scheme::SchemeTag,
+    transaction::{Transaction, TxOutput},
+};
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Sha3_256};
+use sha3::Digest;
 
-const VERSION: u8 = 0x00;
-const ADDRESS_CHECK_SUM_LEN: usize = 4;
-const WALLET_FILE: &str = "./wallet.dat";
+const MNEMONIC_ENTROPY_LEN: usize = 16;
+// 每条链预先派生、还没有分配出去的地址数
+const KEYPOOL_SIZE: usize = 20;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Wallets {
+    // 旧版本随机生成的密钥，没有种子无法恢复
+    #[serde(default)]
     wallets: HashMap<String, Wallet>,
+    // 所有新密钥都从种子派生，钱包加密后只保存密文
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_seed: Option<Sealed>,
+    // 生成种子的助记词，用于备份，和种子一起加密
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_mnemonic: Option<Sealed>,
+    // 每条链下一个要派生的序号
+    #[serde(default)]
+    receive_index: u32,
+    #[serde(default)]
+    change_index: u32,
+    // 已经派生的公钥，包括 keypool 中的地址，锁定时也可以分配地址
+    #[serde(default)]
+    keys: HashMap<String, HdKey>,
+    // 钱包加密后私钥只以密文保存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    master_key: Option<MasterKey>,
+    // 只观察、没有私钥的地址，key 是 base58 格式的地址
+    #[serde(default)]
+    watch_only: HashMap<String, WatchOnly>,
+    // 地址的标签，key 是 base58 格式的地址
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    // 和钱包地址有关的交易，按第一次看到的顺序排列
+    #[serde(default)]
+    transactions: Vec<WalletTx>,
+    // 已经扫描过的最后一个区块
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    synced: Option<SyncPoint>,
+    #[serde(skip)]
+    path: PathBuf,
+    // 本进程中解锁得到的密钥，不写入文件
+    #[serde(skip)]
+    unlocked: Option<[u8; KEY_LEN]>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum KeyChain {
+    Receive,
+    Change,
+}
+
+impl KeyChain {
+    // 派生路径 m/0'/chain'/index'，和 Bitcoin Core 旧版 HD 钱包相同
+    fn path(&self, index: u32) -> String {
+        let chain = match self {
+            KeyChain::Receive => 0,
+            KeyChain::Change => 1,
+        };
+        format!("m/0'/{chain}'/{index}'")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HdKey {
+    pub chain: KeyChain,
+    pub index: u32,
+    pub public_key: Vec<u8>,
+    pub used: bool, // 已经分配出去，不在 keypool 中
+    // 分配地址时选择的格式，keys 中的地址总是 base58 格式
+    #[serde(default)]
+    pub encoding: AddressEncoding,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WatchOnly {
+    // 通过公钥导入时保存规范编码的公钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<Vec<u8>>,
+}
+
+// 钱包记录的交易，金额和手续费根据钱包中的其他记录计算
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletTx {
+    pub tx: Transaction,
+    // 所在区块的高度和 hash，在内存池中时为空
+    pub height: Option<u64>,
+    pub block_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl WalletTx {
+    pub fn confirmations(&self, best_height: u64) -> u64 {
+        self.height.map_or(0, |height| (best_height + 1).saturating_sub(height))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxCategory {
+    Generate,
+    Receive,
+    Send,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SyncPoint {
+    height: u64,
+    hash: String,
 }
 
 impl Wallets {
-    pub fn new_wallets() -> anyhow::Result<Self> {
+    pub fn new_wallets(datadir: &DataDir) -> anyhow::Result<Self> {
         let mut wallets = Self {
+            path: datadir.wallet_path(),
             ..Default::default()
         };
 
@@ -43,36 +152,654 @@ impl Wallets {
 }
 
 impl Wallets {
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new_wallet();
-        let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
-        address
+    // 从 keypool 中分配一个新的收款地址
+    pub fn create_wallet(&mut self, encoding: AddressEncoding) -> anyhow::Result<String> {
+        self.next_address(KeyChain::Receive, encoding)
+    }
+
+    pub fn get_change_address(&mut self, encoding: AddressEncoding) -> anyhow::Result<String> {
+        self.next_address(KeyChain::Change, encoding)
+    }
+
+    // 锁定的钱包只能使用 keypool 中已经派生的地址
+    fn next_address(
+        &mut self,
+        chain: KeyChain,
+        encoding: AddressEncoding,
+    ) -> anyhow::Result<String> {
+        if !self.has_seed() {
+            self.set_mnemonic(&new_mnemonic()?)?;
+        }
+        if self.is_unlocked() {
+            self.top_up_keypool()?;
+        }
+        let (address, key) = self
+            .keys
+            .iter_mut()
+            .filter(|(_, key)| key.chain == chain && !key.used)
+            .min_by_key(|(_, key)| key.index)
+            .ok_or(anyhow!(
+                "Keypool is empty, unlock the wallet with walletpassphrase to refill it"
+            ))?;
+        key.used = true;
+        key.encoding = encoding;
+        let address = address.parse::<Address>()?.with_encoding(encoding).to_string();
+        if self.is_unlocked() {
+            self.top_up_keypool()?;
+        }
+        Ok(address)
     }
 
+    fn has_seed(&self) -> bool {
+        self.seed.is_some() || self.encrypted_seed.is_some()
+    }
+
+    // 使用助记词生成的种子，加密的钱包需要先解锁
+    fn set_mnemonic(&mut self, mnemonic: &Mnemonic) -> anyhow::Result<()> {
+        let seed = mnemonic.to_seed("");
+        let phrase = mnemonic.to_string();
+        if self.master_key.is_some() {
+            let key = self.unlocked_key()?;
+            self.encrypted_seed = Some(crypter::seal(&key, &seed)?);
+            self.encrypted_mnemonic = Some(crypter::seal(&key, phrase.as_bytes())?);
+        } else {
+            self.seed = Some(hex::encode(seed));
+            self.mnemonic = Some(phrase);
+        }
+        Ok(())
+    }
+
+    pub fn get_mnemonic(&self) -> anyhow::Result<String> {
+        match (&self.mnemonic, &self.encrypted_mnemonic) {
+            (Some(phrase), _) => Ok(phrase.clone()),
+            (None, Some(sealed)) => Ok(String::from_utf8(crypter::open(
+                &self.unlocked_key()?,
+                sealed,
+            )?)?),
+            (None, None) => Err(anyhow!("Wallet has no mnemonic")),
+        }
+    }
+
+    // 用助记词恢复种子，只能恢复到还没有密钥的钱包中
+    pub fn restore(&mut self, phrase: &str) -> anyhow::Result<()> {
+        let mnemonic = parse_mnemonic(phrase)?;
+        if self.has_seed() || !self.wallets.is_empty() {
+            return Err(anyhow!("Wallet already has keys, restore into a new data directory"));
+        }
+        self.set_mnemonic(&mnemonic)?;
+        self.top_up_keypool()?;
+        Ok(())
+    }
+
+    // 把链上出现过的地址标记为已使用，并在最后一个使用的地址之后保留 KEYPOOL_SIZE 个地址，
+    // 返回找到的地址数
+    pub fn rescan(&mut self, used_pubkey_hashes: &HashSet<String>) -> anyhow::Result<usize> {
+        let master = ExtendedKey::from_seed(&self.get_seed()?)?;
+        let mut found = 0;
+        loop {
+            let mut marked = 0;
+            for key in self.keys.values_mut().filter(|key| !key.used) {
+                if used_pubkey_hashes.contains(&hex::encode(hash_pubkey(&key.public_key))) {
+                    key.used = true;
+                    marked += 1;
+                }
+            }
+            for chain in [KeyChain::Receive, KeyChain::Change] {
+                let end = self
+                    .keys
+                    .values()
+                    .filter(|key| key.chain == chain && key.used)
+                    .map(|key| key.index as usize + 1)
+                    .max()
+                    .unwrap_or(0)
+                    + KEYPOOL_SIZE;
+                while (self.next_index(chain) as usize) < end {
+                    self.derive_next(&master, chain)?;
+                }
+            }
+            if marked == 0 {
+                break;
+            }
+            found += marked;
+        }
+        Ok(found)
+    }
+
+    fn get_seed(&self) -> anyhow::Result<Vec<u8>> {
+        match (&self.seed, &self.encrypted_seed) {
+            (Some(seed), _) => Ok(hex::decode(seed)?),
+            (None, Some(sealed)) => crypter::open(&self.unlocked_key()?, sealed),
+            (None, None) => Err(anyhow!("Wallet has no seed")),
+        }
+    }
+
+    // 派生新的密钥，直到每条链都有 KEYPOOL_SIZE 个没有分配的地址
+    pub fn top_up_keypool(&mut self) -> anyhow::Result<usize> {
+        let seed = self.get_seed()?;
+        self.fill_keypool(&seed)
+    }
+
+    fn fill_keypool(&mut self, seed: &[u8]) -> anyhow::Result<usize> {
+        let master = ExtendedKey::from_seed(seed)?;
+        let mut derived = 0;
+        for chain in [KeyChain::Receive, KeyChain::Change] {
+            let unused = self
+                .keys
+                .values()
+                .filter(|key| key.chain == chain && !key.used)
+                .count();
+            for _ in unused..KEYPOOL_SIZE {
+                self.derive_next(&master, chain)?;
+                derived += 1;
+            }
+        }
+        Ok(derived)
+    }
+
+    fn next_index(&self, chain: KeyChain) -> u32 {
+        match chain {
+            KeyChain::Receive => self.receive_index,
+            KeyChain::Change => self.change_index,
+        }
+    }
+
+    fn derive_next(&mut self, master: &ExtendedKey, chain: KeyChain) -> anyhow::Result<()> {
+        let index = self.next_index(chain);
+        let key = HdKey {
+            chain,
+            index,
+            public_key: master.derive_path(&chain.path(index))?.public_key()?,
+            used: false,
+            encoding: AddressEncoding::default(),
+        };
+        let address = Address::from_pubkey(&key.public_key, SchemeTag::P256Ecdsa);
+        self.keys.insert(address.to_string(), key);
+        match chain {
+            KeyChain::Receive => self.receive_index += 1,
+            KeyChain::Change => self.change_index += 1,
+        }
+        Ok(())
+    }
+
+    // 返回包含私钥的钱包，加密的钱包没有解锁时返回错误
     pub fn get_wallet(&self, address: &str) -> anyhow::Result<Wallet> {
-        let wallet = self
+        // 两种格式的地址都可以找到同一个密钥
+        let address = address
+            .parse::<Address>()?
+            .with_encoding(AddressEncoding::Base58)
+            .to_string();
+        let address = address.as_str();
+        if self.watch_only.contains_key(address) {
+            return Err(anyhow!(
+                "Address {address} is watch-only, the wallet has no private key to sign for it"
+            ));
+        }
+        if let Some(key) = self.keys.get(address) {
+            let master = ExtendedKey::from_seed(&self.get_seed()?)?;
+            return Ok(Wallet {
+                secret_key: master.derive_path(&key.chain.path(key.index))?.secret_key(),
+                public_key: key.public_key.clone(),
+                scheme: SchemeTag::P256Ecdsa,
+                encrypted_secret: None,
+            });
+        }
+        let mut wallet = self
             .wallets
             .get(address)
             .cloned()
-            .map_or(Err(anyhow!("Get wallet, return None")), |v| Ok(v));
-        wallet
+            .ok_or(anyhow!("Get wallet, return None"))?;
+        if let Some(sealed) = wallet.encrypted_secret.take() {
+            wallet.secret_key = crypter::open(&self.unlocked_key()?, &sealed)?;
+        }
+        Ok(wallet)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.master_key.is_some()
+    }
+
+    // 用口令加密所有私钥，加密后钱包处于锁定状态
+    pub fn encrypt(&mut self, passphrase: &str) -> anyhow::Result<()> {
+        if self.master_key.is_some() {
+            return Err(anyhow!("Wallet is already encrypted, use walletpassphrasechange"));
+        }
+        let (master_key, key) = MasterKey::new(passphrase)?;
+        for wallet in self.wallets.values_mut() {
+            wallet.encrypted_secret = Some(crypter::seal(&key, &wallet.secret_key)?);
+            wallet.secret_key.clear();
+        }
+        if let Some(seed) = self.seed.take() {
+            self.encrypted_seed = Some(crypter::seal(&key, &hex::decode(seed)?)?);
+        }
+        if let Some(phrase) = self.mnemonic.take() {
+            self.encrypted_mnemonic = Some(crypter::seal(&key, phrase.as_bytes())?);
+        }
+        self.master_key = Some(master_key);
+        self.save_to_file()?;
+        self.lock()
+    }
+
+    // 检查口令并在本进程中解锁，同时补充 keypool
+    pub fn unlock(&mut self, passphrase: &str) -> anyhow::Result<()> {
+        let master_key = self
+            .master_key
+            .as_ref()
+            .ok_or(anyhow!("Wallet is not encrypted"))?;
+        let key = master_key.unlock(passphrase)?;
+        self.unlocked = Some(key);
+        if let Some(sealed) = &self.encrypted_seed {
+            let seed = crypter::open(&key, sealed)?;
+            if self.fill_keypool(&seed)? > 0 {
+                self.save_to_file()?;
+            }
+        }
+        Ok(())
+    }
+
+    // 之后的命令在 timeout 秒内通过 agent 使用密钥，密钥不会写到磁盘上
+    pub fn start_agent(&self, timeout: u64) -> anyhow::Result<()> {
+        agent::spawn(&self.agent_path(), &self.unlocked_key()?, timeout)
+    }
+
+    pub fn lock(&mut self) -> anyhow::Result<()> {
+        self.unlocked = None;
+        agent::stop(&self.agent_path())
+    }
+
+    // 用新的口令重新加密所有私钥，完成后钱包处于锁定状态
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> anyhow::Result<()> {
+        let master_key = self
+            .master_key
+            .as_ref()
+            .ok_or(anyhow!("Wallet is not encrypted"))?;
+        let old_key = master_key.unlock(old)?;
+        let (master_key, key) = MasterKey::new(new)?;
+        for wallet in self.wallets.values_mut() {
+            if let Some(sealed) = &wallet.encrypted_secret {
+                let secret_key = crypter::open(&old_key, sealed)?;
+                wallet.encrypted_secret = Some(crypter::seal(&key, &secret_key)?);
+            }
+        }
+        for sealed in [&mut self.encrypted_seed, &mut self.encrypted_mnemonic]
+            .into_iter()
+            .flatten()
+        {
+            let data = crypter::open(&old_key, sealed)?;
+            *sealed = crypter::seal(&key, &data)?;
+        }
+        self.master_key = Some(master_key);
+        self.save_to_file()?;
+        self.lock()
+    }
+
+    fn is_unlocked(&self) -> bool {
+        self.master_key.is_none() || self.unlocked_key().is_ok()
+    }
+
+    fn agent_path(&self) -> PathBuf {
+        self.path.with_extension("unlock")
+    }
+
+    fn unlocked_key(&self) -> anyhow::Result<[u8; KEY_LEN]> {
+        if let Some(key) = self.unlocked {
+            return Ok(key);
+        }
+        agent::request_key(&self.agent_path())?
+            .ok_or_else(|| anyhow!("Wallet is locked, unlock it with walletpassphrase first"))
+    }
+
+    // 导入只观察的地址，清除扫描进度，下次同步时从创世块重新扫描它的交易
+    pub fn import_address(&mut self, address: &Address) -> anyhow::Result<()> {
+        self.import_watch_only(address, None)
+    }
+
+    // 导入公钥对应的地址，返回这个地址
+    pub fn import_pubkey(
+        &mut self,
+        public_key: &[u8],
+        scheme: SchemeTag,
+    ) -> anyhow::Result<Address> {
+        let public_key = scheme
+            .scheme()
+            .encode_pubkey(public_key)
+            .map_err(|e| anyhow!("Invalid public key: {e}"))?;
+        let address = Address::from_pubkey(&public_key, scheme);
+        self.import_watch_only(&address, Some(public_key))?;
+        Ok(address)
+    }
+
+    fn import_watch_only(
+        &mut self,
+        address: &Address,
+        public_key: Option<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let key = address.with_encoding(AddressEncoding::Base58).to_string();
+        if self.wallets.contains_key(&key) || self.keys.contains_key(&key) {
+            return Err(anyhow!(
+                "Address {address} is already in the wallet with its private key"
+            ));
+        }
+        let entry = self.watch_only.entry(key).or_default();
+        if public_key.is_some() {
+            entry.public_key = public_key;
+        }
+        self.synced = None;
+        Ok(())
+    }
+
+    // 有私钥的密钥的 pubkey hash，包括 keypool 中的地址
+    pub fn spendable_pubkey_hashes(&self) -> HashSet<String> {
+        address_pubkey_hashes(self.wallets.keys().chain(self.keys.keys()))
+    }
+
+    pub fn watch_only_pubkey_hashes(&self) -> HashSet<String> {
+        address_pubkey_hashes(self.watch_only.keys())
+    }
+
+    // 钱包跟踪的所有 pubkey hash
+    fn pubkey_hashes(&self) -> HashSet<String> {
+        let mut pubkey_hashes = self.spendable_pubkey_hashes();
+        pubkey_hashes.extend(self.watch_only_pubkey_hashes());
+        pubkey_hashes
+    }
+
+    // 扫描上次之后的区块和内存池，记录和钱包地址有关的交易，
+    // 上次扫描到的区块已经不在链上时从创世块重新扫描
+    pub fn sync(&mut self, bc: &Blockchain) -> anyhow::Result<()> {
+        let mine = self.pubkey_hashes();
+        if mine.is_empty() {
+            return Ok(());
+        }
+        // 被裁剪的区块不会再被回滚，其中的记录一直有效
+        let prune_height = bc.get_store().get_prune_height()?;
+        if let Some(synced) = &self.synced {
+            let headers = bc.headers_in_range(Some(synced.height), Some(synced.height))?;
+            if headers.first().is_none_or(|header| header.hash != synced.hash) {
+                for wtx in self.transactions.iter_mut().filter(|wtx| wtx.height > prune_height) {
+                    wtx.height = None;
+                    wtx.block_hash = None;
+                }
+                self.synced = None;
+            }
+        }
+
+        let mut from = self.synced.as_ref().map(|synced| synced.height + 1);
+        // 被裁剪的区块只剩区块头，从 UTXO 集合中找回其中属于钱包的未花费输出
+        if let Some(prune_height) = prune_height {
+            if from.is_none_or(|from| from <= prune_height) {
+                self.add_pruned_outputs(bc, prune_height, &mine)?;
+                from = Some(prune_height + 1);
+            }
+        }
+        for block in bc.blocks_in_range(from, None)? {
+            let block = block?;
+            for tx in block.transactions.iter() {
+                self.add_transaction(tx, Some((block.get_height(), block.get_hash())), &mine);
+            }
+            self.synced = Some(SyncPoint {
+                height: block.get_height(),
+                hash: block.get_hash(),
+            });
+        }
+
+        let pending = Mempool::new(bc.clone()).transactions()?;
+        for tx in pending.iter() {
+            self.add_transaction(tx, None, &mine);
+        }
+        // 既不在链上也不在内存池中的交易已经被替换或者丢弃
+        self.transactions
+            .retain(|wtx| wtx.height.is_some() || pending.iter().any(|tx| tx.id == wtx.tx.id));
+        Ok(())
+    }
+
+    // 被裁剪的交易只包含仍未花费的输出，已经花费的输出和花费记录无法恢复
+    fn add_pruned_outputs(
+        &mut self,
+        bc: &Blockchain,
+        prune_height: u64,
+        mine: &HashSet<String>,
+    ) -> anyhow::Result<()> {
+        let hashes: HashMap<u64, String> = bc
+            .headers_in_range(None, Some(prune_height))?
+            .into_iter()
+            .map(|header| (header.height, header.hash))
+            .collect();
+        let mut pruned: HashMap<String, (u64, Transaction)> = HashMap::new();
+        for ((txid, vout), coin) in bc.get_store().utxos()? {
+            let known = self.transactions.iter().any(|wtx| wtx.tx.id == txid);
+            let is_mine = output_pubkey_hashes(&coin.output).iter().any(|h| mine.contains(h));
+            if coin.height > prune_height || known || !is_mine {
+                continue;
+            }
+            let (_, tx) = pruned.entry(txid.clone()).or_insert((
+                coin.height,
+                Transaction {
+                    id: txid,
+                    ..Default::default()
+                },
+            ));
+            if tx.vout.len() <= vout as usize {
+                tx.vout.resize(vout as usize + 1, TxOutput::default());
+            }
+            tx.vout[vout as usize] = coin.output;
+        }
+        for (height, tx) in pruned.into_values() {
+            self.transactions.push(WalletTx {
+                tx,
+                height: Some(height),
+                block_hash: hashes.get(&height).cloned(),
+                note: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn add_transaction(
+        &mut self,
+        tx: &Transaction,
+        block: Option<(u64, String)>,
+        mine: &HashSet<String>,
+    ) {
+        let spends = !tx.is_coinbase()
+            && tx
+                .vin
+                .iter()
+                .any(|vin| mine.contains(&hex::encode(hash_pubkey(&vin.pubkey))));
+        let receives = tx
+            .vout
+            .iter()
+            .flat_map(output_pubkey_hashes)
+            .any(|pubkey_hash| mine.contains(&pubkey_hash));
+        if !spends && !receives {
+            return;
+        }
+        let (height, block_hash) = block.unzip();
+        match self.transactions.iter_mut().find(|wtx| wtx.tx.id == tx.id) {
+            Some(wtx) => {
+                if height.is_some() {
+                    wtx.height = height;
+                    wtx.block_hash = block_hash;
+                }
+            }
+            None => self.transactions.push(WalletTx {
+                tx: tx.clone(),
+                height,
+                block_hash,
+                note: None,
+            }),
+        }
+    }
+
+    // 按高度从新到旧排列，未确认的在最前面
+    pub fn list_transactions(&self) -> Vec<&WalletTx> {
+        let mut txs: Vec<&WalletTx> = self.transactions.iter().rev().collect();
+        txs.sort_by_key(|wtx| std::cmp::Reverse(wtx.height.unwrap_or(u64::MAX)));
+        txs
+    }
+
+    pub fn get_transaction(&self, txid: &str) -> anyhow::Result<&WalletTx> {
+        self.transactions
+            .iter()
+            .find(|wtx| wtx.tx.id == txid)
+            .ok_or(anyhow!("Transaction {txid} is not in the wallet"))
+    }
+
+    pub fn set_note(&mut self, txid: &str, note: &str) -> anyhow::Result<()> {
+        let wtx = self
+            .transactions
+            .iter_mut()
+            .find(|wtx| wtx.tx.id == txid)
+            .ok_or(anyhow!("Transaction {txid} is not in the wallet"))?;
+        wtx.note = Some(note.to_string()).filter(|note| !note.is_empty());
+        Ok(())
+    }
+
+    // 空标签表示删除
+    pub fn set_label(&mut self, address: &Address, label: &str) {
+        let address = address.with_encoding(AddressEncoding::Base58).to_string();
+        if label.is_empty() {
+            self.labels.remove(&address);
+        } else {
+            self.labels.insert(address, label.to_string());
+        }
+    }
+
+    pub fn get_label(&self, address: &Address) -> Option<&str> {
+        let address = address.with_encoding(AddressEncoding::Base58).to_string();
+        self.labels.get(&address).map(|label| label.as_str())
+    }
+
+    // 输出的收款地址，钱包的地址使用分配时选择的格式
+    pub fn output_address(&self, out: &TxOutput) -> anyhow::Result<Address> {
+        let (pubkey_hash, scheme) = out.receiver();
+        let address = Address::new(&hex::decode(pubkey_hash)?, scheme)?;
+        Ok(match self.keys.get(&address.to_string()) {
+            Some(key) => address.with_encoding(key.encoding),
+            None => address,
+        })
+    }
+
+    // 支付到钱包找零链上地址的输出
+    pub fn is_change(&self, out: &TxOutput) -> bool {
+        let (pubkey_hash, scheme) = out.receiver();
+        let address = hex::decode(pubkey_hash)
+            .map_err(anyhow::Error::from)
+            .and_then(|pubkey_hash| Address::new(&pubkey_hash, scheme));
+        match address {
+            Ok(address) if out.htlc.is_none() => self
+                .keys
+                .get(&address.to_string())
+                .is_some_and(|key| key.chain == KeyChain::Change),
+            _ => false,
+        }
+    }
+
+    pub fn is_mine(&self, out: &TxOutput) -> bool {
+        let (pubkey_hash, _) = out.receiver();
+        self.pubkey_hashes().contains(pubkey_hash)
+    }
+
+    pub fn is_watch_only(&self, out: &TxOutput) -> bool {
+        let (pubkey_hash, _) = out.receiver();
+        self.watch_only_pubkey_hashes().contains(pubkey_hash)
+    }
+
+    // 交易的输入或输出中有只观察的地址
+    pub fn involves_watch_only(&self, tx: &Transaction) -> bool {
+        let watch_only = self.watch_only_pubkey_hashes();
+        let spends = !tx.is_coinbase()
+            && tx
+                .vin
+                .iter()
+                .any(|vin| watch_only.contains(&hex::encode(hash_pubkey(&vin.pubkey))));
+        spends || tx.vout.iter().any(|out| watch_only.contains(out.receiver().0))
+    }
+
+    // 钱包记录中的交易引用的输出，不属于钱包的交易找不到
+    fn prev_output(&self, txid: &str, vout: isize) -> Option<&TxOutput> {
+        self.transactions
+            .iter()
+            .find(|wtx| wtx.tx.id == txid)
+            .and_then(|wtx| wtx.tx.vout.get(vout as usize))
+    }
+
+    // 支付给钱包地址的金额，包括找零
+    pub fn received(&self, tx: &Transaction) -> isize {
+        let mine = self.pubkey_hashes();
+        tx.vout
+            .iter()
+            .filter(|out| mine.contains(out.receiver().0))
+            .map(|out| out.value)
+            .sum()
+    }
+
+    // 花费的钱包输出的金额
+    pub fn sent(&self, tx: &Transaction) -> isize {
+        if tx.is_coinbase() {
+            return 0;
+        }
+        let mine = self.pubkey_hashes();
+        tx.vin
+            .iter()
+            .filter_map(|vin| self.prev_output(&vin.txid, vin.vout))
+            .filter(|out| mine.contains(out.receiver().0))
+            .map(|out| out.value)
+            .sum()
+    }
+
+    // 所有输入引用的输出都在钱包记录中时才能算出手续费
+    pub fn fee(&self, tx: &Transaction) -> Option<isize> {
+        if tx.is_coinbase() {
+            return None;
+        }
+        let inputs = tx
+            .vin
+            .iter()
+            .map(|vin| self.prev_output(&vin.txid, vin.vout).map(|out| out.value))
+            .sum::<Option<isize>>()?;
+        Some(inputs - tx.vout.iter().map(|out| out.value).sum::<isize>())
+    }
+
+    pub fn category(&self, tx: &Transaction) -> TxCategory {
+        if tx.is_coinbase() {
+            TxCategory::Generate
+        } else if self.sent(tx) > 0 {
+            TxCategory::Send
+        } else {
+            TxCategory::Receive
+        }
+    }
+
+    // 已经分配出去的地址，不包括 keypool 中的地址
+    pub fn get_addresses(&self) -> Vec<String> {
+        let hd_addresses = self.keys.iter().filter(|(_, key)| key.used).map(|(a, key)| {
+            match a.parse::<Address>() {
+                Ok(address) => address.with_encoding(key.encoding).to_string(),
+                Err(_) => a.clone(),
+            }
+        });
+        let mut addresses: Vec<String> = self.wallets.keys().cloned().chain(hd_addresses).collect();
+        addresses.sort();
+        addresses
     }
 
     pub fn save_to_file(&self) -> io::Result<()> {
-        // 也可以直接使用 fs::write("path", "data");
+        // 先写入临时文件再替换，读取钱包的进程不会看到写了一半的文件
+        let tmp_path = self.path.with_extension("tmp");
         let mut file = OpenOptions::new()
             .write(true)
-            // .append(true)
             .create(true)
-            .open(WALLET_FILE)?;
+            .truncate(true)
+            .open(&tmp_path)?;
 
         let data = serde_json::to_string(self)?;
 
         file.write_all(data.as_bytes()).map_err(|e| {
             println!("Write wallets to file err: {e}");
             e
-        })
+        })?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
     }
 
     pub fn load_from_file(&mut self) -> anyhow::Result<()> {
@@ -80,7 +807,8 @@ impl Wallets {
             .read(true)
             .write(true)
             .create(true)
-            .open(WALLET_FILE)?;
+            .truncate(false)
+            .open(&self.path)?;
 
         let mut buf = String::new();
         file.read_to_string(&mut buf).map_err(|e| {
@@ -90,7 +818,10 @@ impl Wallets {
 
         if !buf.is_empty() {
             let wallets = serde_json::from_str::<Wallets>(buf.as_str())?;
-            self.wallets = wallets.wallets;
+            *self = Self {
+                path: self.path.clone(),
+                ..wallets
+            };
         }
         Ok(())
     }
@@ -98,73 +829,88 @@ impl Wallets {
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct Wallet {
-    pub secret_key: Vec<u8>,
+    pub secret_key: Vec<u8>, // 钱包加密后为空
     pub public_key: Vec<u8>,
+    #[serde(default)]
+    pub scheme: SchemeTag,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_secret: Option<Sealed>,
 }
 
 impl Wallet {
     pub fn new_wallet() -> Self {
-        let (secret_key, public_key) = new_key_pair();
+        Self::new_wallet_with_scheme(SchemeTag::default())
+    }
+
+    pub fn new_wallet_with_scheme(scheme: SchemeTag) -> Self {
+        let (secret_key, public_key) = scheme.scheme().new_key_pair();
         Self {
             secret_key,
             public_key,
+            scheme,
+            encrypted_secret: None,
         }
     }
 }
 
 impl Wallet {
-    // version + public_key_hash + check_sum => base58
-    pub fn get_address(&self) -> String {
-        let mut pubkey_hash = hash_pubkey(&self.public_key);
-
-        let mut versioned_payload = vec![];
-        versioned_payload.push(VERSION);
-        versioned_payload.append(&mut pubkey_hash);
-        let check_sum: Vec<u8> = check_sum(&versioned_payload);
-
-        versioned_payload.extend_from_slice(&check_sum[..ADDRESS_CHECK_SUM_LEN]);
+    pub fn address(&self) -> Address {
+        Address::from_pubkey(&self.public_key, self.scheme)
+    }
 
-        versioned_payload.to_base58()
+    pub fn get_address(&self) -> String {
+        self.address().to_string()
     }
 }
 
-pub fn hash_pubkey(pubkey: &Vec<u8>) -> Vec<u8> {
-    let pubkey_hash = sha256::digest(pubkey);
-    Ripemd160::digest(pubkey_hash).to_vec()
+// 12 个单词的助记词
+fn new_mnemonic() -> anyhow::Result<Mnemonic> {
+    let mut entropy = [0u8; MNEMONIC_ENTROPY_LEN];
+    OsRng.fill_bytes(&mut entropy);
+    Ok(Mnemonic::from_entropy(&entropy)?)
 }
 
-fn check_sum(payload: &Vec<u8>) -> Vec<u8> {
-    let first_sha = sha256::digest(payload);
-    sha256::digest(first_sha).into_bytes()
+// 检查单词是否在词表中以及校验和，大小写和多余的空白不影响结果
+pub fn parse_mnemonic(phrase: &str) -> anyhow::Result<Mnemonic> {
+    let words: Vec<String> = phrase.split_whitespace().map(|w| w.to_lowercase()).collect();
+    Mnemonic::parse_in_normalized(Language::English, &words.join(" ")).map_err(|e| match e {
+        bip39::Error::UnknownWord(i) => {
+            anyhow!("Unknown mnemonic word '{}' at position {}", words[i], i + 1)
+        }
+        bip39::Error::InvalidChecksum => {
+            anyhow!("Mnemonic checksum does not match, check the words and their order")
+        }
+        e => anyhow!("Invalid mnemonic: {e}"),
+    })
 }
 
-fn new_key_pair() -> (Vec<u8>, Vec<u8>) {
-    let signing_key = SigningKey::random(&mut OsRng);
-    let verifying_key = signing_key.verifying_key();
-
-    let secret_key: SecretKey<NistP256> = signing_key.into();
-    let pubkey = secret_key.public_key();
-    (
-        secret_key.to_bytes().to_vec(),
-        pubkey.to_sec1_bytes().to_vec(),
-    )
+fn address_pubkey_hashes<'a>(addresses: impl Iterator<Item = &'a String>) -> HashSet<String> {
+    addresses
+        .filter_map(|address| address.parse::<Address>().ok())
+        .map(|address| address.pubkey_hash())
+        .collect()
 }
 
-pub fn pubkey_hash_from_base58(address: &str) -> anyhow::Result<String> {
-    match address.from_base58() {
-        Ok(pubkey_hash) => {
-            let pubkey_hash = &pubkey_hash[1..pubkey_hash.len() - 4];
-            Ok(hex::encode(pubkey_hash.to_vec()))
-        }
-        Err(e) => Err(anyhow!("Decode address to pubkey hash err:{:?}", e)),
-    }
+pub fn hash_pubkey(pubkey: &Vec<u8>) -> Vec<u8> {
+    let pubkey_hash = sha256::digest(pubkey);
+    Ripemd160::digest(pubkey_hash).to_vec()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::wallet::hash_pubkey;
+    use std::collections::HashSet;
 
-    use super::Wallet;
+    use crate::{
+        address::{Address, AddressEncoding},
+        config::Config,
+        mempool::Mempool,
+        store::ChainStore,
+        testutil::{chain_to, spend},
+        transaction::TxOutput,
+        wallet::hash_pubkey,
+    };
+
+    use super::{parse_mnemonic, TxCategory, Wallet, Wallets, KEYPOOL_SIZE};
 
     #[test]
     fn test_get_address() {
@@ -178,4 +924,236 @@ mod test {
 
         assert_eq!(pubkey.len(), 20);
     }
+
+    #[test]
+    fn test_encrypt_wallet() {
+        let dir = std::env::temp_dir().join(format!("btc-wallet-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut wallets = Wallets {
+            path: dir.join("wallet.dat"),
+            ..Default::default()
+        };
+        let address = wallets.create_wallet(AddressEncoding::Base58).unwrap();
+        let secret_key = wallets.get_wallet(&address).unwrap().secret_key;
+        let seed = wallets.seed.clone().unwrap();
+
+        wallets.encrypt("correct horse").unwrap();
+        let data = std::fs::read_to_string(dir.join("wallet.dat")).unwrap();
+        assert!(!data.contains(&seed));
+        let err = wallets.get_wallet(&address).unwrap_err().to_string();
+        assert!(err.contains("Wallet is locked"), "{err}");
+        // 锁定时从 keypool 中分配地址
+        let pooled = wallets.create_wallet(AddressEncoding::Base58).unwrap();
+        assert!(wallets.get_wallet(&pooled).is_err());
+        assert!(wallets.unlock("wrong").is_err());
+
+        wallets.unlock("correct horse").unwrap();
+        assert_eq!(wallets.get_wallet(&address).unwrap().secret_key, secret_key);
+        let second = wallets.create_wallet(AddressEncoding::Base58).unwrap();
+        assert!(!wallets.get_wallet(&second).unwrap().secret_key.is_empty());
+
+        wallets.change_passphrase("correct horse", "battery staple").unwrap();
+        assert!(wallets.get_wallet(&address).is_err());
+        assert!(wallets.unlock("correct horse").is_err());
+        wallets.unlock("battery staple").unwrap();
+        assert_eq!(wallets.get_wallet(&address).unwrap().secret_key, secret_key);
+        // 锁定后私钥不能使用，解锁状态不会留在磁盘上
+        wallets.lock().unwrap();
+        assert!(wallets.get_wallet(&address).is_err());
+        assert!(!dir.join("wallet.unlock").exists());
+        wallets.unlock("battery staple").unwrap();
+        assert_eq!(wallets.get_wallet(&address).unwrap().secret_key, secret_key);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_hd_keypool() {
+        let mut wallets = Wallets::default();
+        let first = wallets.create_wallet(AddressEncoding::Base58).unwrap();
+        let change = wallets.get_change_address(AddressEncoding::Base58).unwrap();
+        assert_eq!(wallets.get_addresses().len(), 2);
+        assert_eq!(wallets.keys.len(), 2 * KEYPOOL_SIZE + 2);
+
+        // 相同的种子派生出相同的地址
+        let mut restored = Wallets {
+            seed: wallets.seed.clone(),
+            ..Default::default()
+        };
+        assert_eq!(restored.create_wallet(AddressEncoding::Base58).unwrap(), first);
+        assert_eq!(restored.get_change_address(AddressEncoding::Base58).unwrap(), change);
+        assert_eq!(
+            restored.get_wallet(&first).unwrap().secret_key,
+            wallets.get_wallet(&first).unwrap().secret_key
+        );
+        assert_ne!(wallets.create_wallet(AddressEncoding::Base58).unwrap(), first);
+
+        // bech32 地址和对应的 base58 地址是同一个密钥
+        let bech32 = wallets.create_wallet(AddressEncoding::Bech32).unwrap();
+        assert!(wallets.get_addresses().contains(&bech32));
+        let base58 = bech32
+            .parse::<Address>()
+            .unwrap()
+            .with_encoding(AddressEncoding::Base58)
+            .to_string();
+        assert_eq!(
+            wallets.get_wallet(&bech32).unwrap().secret_key,
+            wallets.get_wallet(&base58).unwrap().secret_key
+        );
+    }
+
+    #[test]
+    fn test_restore_from_mnemonic() {
+        let valid = format!("{} about", "abandon ".repeat(11));
+        assert!(parse_mnemonic(&valid.to_uppercase()).is_ok());
+        let err = parse_mnemonic(&"abandon ".repeat(12)).unwrap_err().to_string();
+        assert!(err.contains("checksum"), "{err}");
+        let err = parse_mnemonic(&valid.replace("about", "abot")).unwrap_err().to_string();
+        assert!(err.contains("'abot' at position 12"), "{err}");
+
+        let mut wallets = Wallets::default();
+        let addresses: Vec<String> = (0..3)
+            .map(|_| wallets.create_wallet(AddressEncoding::Base58).unwrap())
+            .collect();
+        let phrase = wallets.get_mnemonic().unwrap();
+
+        // 只有第三个地址在链上出现过
+        let key = &wallets.keys[&addresses[2]];
+        let used = HashSet::from([hex::encode(hash_pubkey(&key.public_key))]);
+        let mut restored = Wallets::default();
+        restored.restore(&phrase).unwrap();
+        assert_eq!(restored.rescan(&used).unwrap(), 1);
+        assert_eq!(restored.get_addresses(), vec![addresses[2].clone()]);
+        assert_eq!(
+            restored.get_wallet(&addresses[2]).unwrap().secret_key,
+            wallets.get_wallet(&addresses[2]).unwrap().secret_key
+        );
+        assert!(restored.restore(&phrase).is_err());
+    }
+
+    #[test]
+    fn test_sync_transactions() {
+        let mut wallets = Wallets::default();
+        let alice = wallets.create_wallet(AddressEncoding::Base58).unwrap();
+        let bob = Wallet::new_wallet();
+        let (_, mut bc, coinbase) = chain_to(alice.clone(), Config::default());
+        wallets.sync(&bc).unwrap();
+        assert_eq!(wallets.list_transactions()[0].tx.id, coinbase.id);
+        assert_eq!(wallets.category(&coinbase), TxCategory::Generate);
+        assert_eq!(wallets.received(&coinbase), 50);
+
+        let key = wallets.get_wallet(&alice).unwrap();
+        let outputs = vec![
+            TxOutput::new_tx_output(20, &bob.address()),
+            TxOutput::new_tx_output(28, &key.address()),
+        ];
+        let tx = spend(&key, &coinbase, 0, outputs);
+        let mempool = Mempool::new(bc.clone());
+        mempool.add(tx.clone()).unwrap();
+        wallets.sync(&bc).unwrap();
+        let wtx = wallets.get_transaction(&tx.id).unwrap();
+        assert_eq!(wtx.confirmations(0), 0);
+        assert_eq!(wallets.category(&tx), TxCategory::Send);
+        assert_eq!(wallets.received(&tx) - wallets.sent(&tx), -22);
+        assert_eq!(wallets.fee(&tx), Some(2));
+
+        let block = bc.mine_block(vec![tx.clone()]).unwrap();
+        mempool.remove_for_block(&block).unwrap();
+        bc.mine_block(vec![]).unwrap();
+        wallets.set_note(&tx.id, "rent").unwrap();
+        wallets.set_label(&bob.address(), "bob");
+        wallets.sync(&bc).unwrap();
+        let ids: Vec<&str> = wallets.list_transactions().iter().map(|w| w.tx.id.as_str()).collect();
+        assert_eq!(ids, vec![&tx.id, &wallets.transactions[0].tx.id]);
+        let wtx = wallets.get_transaction(&tx.id).unwrap();
+        assert_eq!((wtx.height, wtx.confirmations(2)), (Some(1), 2));
+        assert_eq!(wtx.note.as_deref(), Some("rent"));
+        let bob_address = wallets.output_address(&tx.vout[0]).unwrap();
+        assert_eq!(wallets.get_label(&bob_address), Some("bob"));
+
+        // 回滚后重新扫描，不在链上也不在内存池中的交易被删除，备注随记录一起保留
+        bc.rollback("0").unwrap();
+        mempool.add(tx.clone()).unwrap();
+        wallets.sync(&bc).unwrap();
+        let wtx = wallets.get_transaction(&tx.id).unwrap();
+        assert_eq!((wtx.height, wtx.note.as_deref()), (None, Some("rent")));
+        mempool.remove_for_block(&block).unwrap();
+        wallets.sync(&bc).unwrap();
+        assert!(wallets.get_transaction(&tx.id).is_err());
+        assert_eq!(wallets.list_transactions().len(), 1);
+    }
+
+    #[test]
+    fn test_sync_pruned_chain() {
+        let mut wallets = Wallets::default();
+        let alice = wallets.create_wallet(AddressEncoding::Base58).unwrap();
+        let key = wallets.get_wallet(&alice).unwrap();
+        let config = Config {
+            prune: Some(6),
+            ..Default::default()
+        };
+        let (store, mut bc, coinbase) = chain_to(alice, config);
+        let outputs = vec![
+            TxOutput::new_tx_output(20, &key.address()),
+            TxOutput::new_tx_output(30, &key.address()),
+        ];
+        let split = spend(&key, &coinbase, 0, outputs);
+        bc.mine_block(vec![split.clone()]).unwrap();
+        for _ in 0..7 {
+            bc.mine_block(vec![]).unwrap();
+        }
+        assert_eq!(store.get_prune_height().unwrap(), Some(2));
+
+        // 被裁剪的区块中只能找回未花费的输出，已经花费的 coinbase 找不到了
+        wallets.sync(&bc).unwrap();
+        let txs = wallets.list_transactions();
+        assert_eq!(txs.len(), 1);
+        assert_eq!((txs[0].tx.id.as_str(), txs[0].height), (split.id.as_str(), Some(1)));
+        assert_eq!(wallets.received(&txs[0].tx), 50);
+        assert_eq!(txs[0].block_hash, Some(bc.find_block("1").unwrap().hash));
+
+        // 导入的地址同样从 UTXO 集合中找回被裁剪区块中的输出
+        let cold = Wallet::new_wallet();
+        let to_cold = vec![TxOutput::new_tx_output(20, &cold.address())];
+        bc.mine_block(vec![spend(&key, &split, 0, to_cold)]).unwrap();
+        for _ in 0..6 {
+            bc.mine_block(vec![]).unwrap();
+        }
+        wallets.import_address(&cold.address()).unwrap();
+        wallets.sync(&bc).unwrap();
+        let received = wallets.list_transactions()[0];
+        assert_eq!(received.height, Some(9));
+        assert!(received.height <= store.get_prune_height().unwrap());
+        assert!(wallets.involves_watch_only(&received.tx));
+    }
+
+    #[test]
+    fn test_watch_only() {
+        let mut wallets = Wallets::default();
+        let own = wallets.create_wallet(AddressEncoding::Base58).unwrap();
+        let cold = Wallet::new_wallet();
+        let (_, bc, _) = chain_to(cold.get_address(), Config::default());
+        wallets.sync(&bc).unwrap();
+        assert!(wallets.list_transactions().is_empty());
+
+        // 导入后从头扫描，找到导入之前的交易
+        let bech32 = cold.address().with_encoding(AddressEncoding::Bech32);
+        wallets.import_address(&bech32).unwrap();
+        wallets.sync(&bc).unwrap();
+        let coinbase = &wallets.list_transactions()[0].tx;
+        assert!(wallets.involves_watch_only(coinbase));
+        assert!(wallets.is_watch_only(&coinbase.vout[0]));
+        assert!(wallets.watch_only_pubkey_hashes().contains(&cold.address().pubkey_hash()));
+        assert!(!wallets.spendable_pubkey_hashes().contains(&cold.address().pubkey_hash()));
+
+        let err = wallets.get_wallet(&cold.get_address()).unwrap_err().to_string();
+        assert!(err.contains("watch-only"), "{err}");
+        assert!(wallets.get_wallet(&bech32.to_string()).is_err());
+
+        // 公钥导入同一个地址时补充公钥，有私钥的地址不能导入
+        let address = wallets.import_pubkey(&cold.public_key, cold.scheme).unwrap();
+        assert_eq!(address, cold.address());
+        assert_eq!(wallets.watch_only.len(), 1);
+        assert!(wallets.import_pubkey(&[4, 1, 2], cold.scheme).is_err());
+        assert!(wallets.import_address(&own.parse().unwrap()).is_err());
+    }
 }
@@ -0,0 +1,53 @@
+// BTC_DATADIR 只设置在子进程中，不修改测试进程的环境变量
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("btc-cli-{name}-{}", std::process::id()))
+}
+
+fn run(env_datadir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_simple-btc-rust"))
+        .env("BTC_DATADIR", env_datadir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_datadir_from_env() {
+    let (from_env, from_arg) = (temp_path("env"), temp_path("arg"));
+
+    // 没有 --datadir 时使用环境变量
+    let output = run(&from_env, &["createwallet"]);
+    assert!(output.status.success());
+    assert!(from_env.join("wallet.dat").exists());
+    assert!(from_env.join("debug.log").exists());
+
+    // 命令行参数优先于环境变量，放在子命令之后也可以
+    let arg = from_arg.to_str().unwrap();
+    assert!(run(&from_env, &["createwallet", "--datadir", arg])
+        .status
+        .success());
+    assert!(from_arg.join("wallet.dat").exists());
+
+    // error 日志同时写入 debug.log 和 stderr
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let address = stdout.split_whitespace().last().unwrap();
+    assert!(run(&from_env, &["createblockchain", "-a", address])
+        .status
+        .success());
+    let output = run(&from_env, &["createblockchain", "-a", address]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("ERROR"), "{stderr}");
+    let log = fs::read_to_string(from_env.join("debug.log")).unwrap();
+    assert!(log.contains("ERROR"), "{log}");
+
+    for dir in [from_env, from_arg] {
+        fs::remove_dir_all(dir).unwrap();
+    }
+}